@@ -0,0 +1,69 @@
+-- A database as written by envoyproxy from just before the schema was versioned
+PRAGMA foreign_keys=OFF;
+BEGIN TRANSACTION;
+CREATE TABLE history(
+                kind INTEGER NOT NULL,
+                timestamp BIGINT NOT NULL,
+                value BIGINT NOT NULL,
+                PRIMARY KEY (kind, timestamp)
+            );
+INSERT INTO history VALUES(0,1748736000,1500);
+INSERT INTO history VALUES(0,1748736060,1600);
+CREATE TABLE history_5m(
+                kind INTEGER NOT NULL,
+                timestamp BIGINT NOT NULL,
+                sum BIGINT NOT NULL,
+                count BIGINT NOT NULL,
+                min BIGINT,
+                max BIGINT,
+                PRIMARY KEY (kind, timestamp)
+            );
+INSERT INTO history_5m VALUES(0,1748732400,3000,2,1000,2000);
+CREATE TABLE history_hourly(
+                kind INTEGER NOT NULL,
+                timestamp BIGINT NOT NULL,
+                sum BIGINT NOT NULL,
+                count BIGINT NOT NULL,
+                min BIGINT,
+                max BIGINT,
+                PRIMARY KEY (kind, timestamp)
+            );
+INSERT INTO history_hourly VALUES(0,1748732400,3000,2,1000,2000);
+CREATE TABLE history_daily(
+                kind INTEGER NOT NULL,
+                timestamp BIGINT NOT NULL,
+                sum BIGINT NOT NULL,
+                count BIGINT NOT NULL,
+                min BIGINT,
+                max BIGINT,
+                PRIMARY KEY (kind, timestamp)
+            );
+INSERT INTO history_daily VALUES(0,1748649600,3000,2,1000,2000);
+CREATE TABLE history_monthly(
+                kind INTEGER NOT NULL,
+                timestamp BIGINT NOT NULL,
+                sum BIGINT NOT NULL,
+                count BIGINT NOT NULL,
+                min BIGINT,
+                max BIGINT,
+                PRIMARY KEY (kind, timestamp)
+            );
+INSERT INTO history_monthly VALUES(0,1746057600,3000,2,1000,2000);
+CREATE TABLE inverter_history(
+                serial TEXT NOT NULL,
+                timestamp BIGINT NOT NULL,
+                watts BIGINT NOT NULL,
+                PRIMARY KEY (serial, timestamp)
+            );
+INSERT INTO inverter_history VALUES('122233445566',1748736000,231);
+CREATE TABLE battery_history(
+                serial TEXT NOT NULL,
+                timestamp BIGINT NOT NULL,
+                soc INTEGER NOT NULL,
+                PRIMARY KEY (serial, timestamp)
+            );
+INSERT INTO battery_history VALUES('122107012345',1748736000,43);
+CREATE INDEX idx_history_on_timestamp ON history(timestamp);
+CREATE INDEX idx_inverter_history_on_timestamp ON inverter_history(timestamp);
+CREATE INDEX idx_battery_history_on_timestamp ON battery_history(timestamp);
+COMMIT;
@@ -0,0 +1,13 @@
+-- A database as written by envoyproxy from before history was rolled up or the schema was versioned
+PRAGMA foreign_keys=OFF;
+BEGIN TRANSACTION;
+CREATE TABLE history(
+                kind INTEGER NOT NULL,
+                timestamp BIGINT NOT NULL,
+                value BIGINT NOT NULL,
+                PRIMARY KEY (kind, timestamp)
+            );
+INSERT INTO history VALUES(0,1748736000,1500);
+INSERT INTO history VALUES(0,1748736060,1600);
+CREATE INDEX idx_history_on_timestamp ON history(timestamp);
+COMMIT;
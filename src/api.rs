@@ -1,10 +1,32 @@
-use axum::{extract::State, response::IntoResponse};
+use axum::{
+    extract::{
+        Query, State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    response::{
+        IntoResponse,
+        sse::{self, Sse},
+    },
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use futures_util::{Stream, StreamExt};
 use promformat::Metrics;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
+use std::collections::BTreeMap;
+use std::convert::Infallible;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
 
-use crate::state::{self, AppState, Inventory, SystemState};
+use crate::envoy_api::{GridState, RelayState};
+use crate::export::{self, ExportFormat, ExportQuery};
+use crate::refresh::RefreshOutcome;
+use crate::state::{
+    self, AppState, DeviceInfo, EndpointStatus, HistoryKind, Inventory, Inverter, LATENCY_BUCKETS,
+    StateEvent, SystemState, TaskStatus,
+};
+use crate::time_series::{Aggregation, HistoryPoint, Step};
 
 #[derive(Serialize, Debug)]
 struct ResponseBody {
@@ -12,6 +34,9 @@ struct ResponseBody {
     state: SystemState,
     #[serde(flatten)]
     inventory: Inventory,
+    device: Option<DeviceInfo>,
+    inverters: Vec<Inverter>,
+    endpoints: BTreeMap<&'static str, EndpointStatus>,
     history: state::HistoryResponse,
 }
 
@@ -30,14 +55,42 @@ impl HealthcheckResponse {
     }
 }
 
-pub async fn healthcheck(State(state): State<Arc<AppState>>) -> impl IntoResponse {
-    let state = state.system_state.read().await;
+pub async fn healthcheck(State(raw_state): State<Arc<AppState>>) -> impl IntoResponse {
+    let expires_at = raw_state
+        .tokens
+        .current()
+        .await
+        .map(|token| token.expires_at());
+    let token_message = expires_at
+        .map(|e| format!("token expires {}", e))
+        .unwrap_or_else(|| "no token".to_owned());
+    if let Some(failed_since) = *raw_state.auth_failed_since.read().await {
+        return (
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            axum::Json(HealthcheckResponse::new(
+                false,
+                format!(
+                    "envoy has rejected our credentials since {} ({})",
+                    failed_since, token_message
+                ),
+            )),
+        );
+    }
+    if let Some(expires_at) = expires_at
+        && expires_at - raw_state.token_expiry_warning < Utc::now()
+    {
+        return (
+            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            axum::Json(HealthcheckResponse::new(false, token_message)),
+        );
+    }
+    let state = raw_state.system_state.read().await;
     if let Some(last_update) = state.last_update.as_ref() {
         (
             axum::http::StatusCode::OK,
             axum::Json(HealthcheckResponse::new(
                 true,
-                format!("data fetched as of {}", last_update),
+                format!("data fetched as of {}; {}", last_update, token_message),
             )),
         )
     } else {
@@ -48,12 +101,27 @@ pub async fn healthcheck(State(state): State<Arc<AppState>>) -> impl IntoRespons
     }
 }
 
+#[derive(Deserialize, Debug)]
+pub struct MetricsQuery {
+    #[serde(default)]
+    refresh: bool,
+}
+
 pub async fn metrics_json(
     State(state): State<Arc<AppState>>,
+    Query(query): Query<MetricsQuery>,
 ) -> axum::response::Json<impl Serialize> {
+    if query.refresh {
+        // if this fails or is too soon, just serve what we already have
+        let outcome = state.refresh.request().await;
+        tracing::debug!(?outcome, "refreshed for /metrics.json");
+    }
     let response_body = ResponseBody {
         state: state.system_state.read().await.clone(),
         inventory: state.inventory.read().await.clone(),
+        device: state.device_info.read().await.clone(),
+        inverters: state.inverters.read().await.clone(),
+        endpoints: state.endpoints.read().await.clone(),
         history: state.history().await,
     };
     axum::Json(response_body)
@@ -62,13 +130,51 @@ pub async fn metrics_json(
 pub async fn metrics_prom(State(raw_state): State<Arc<AppState>>) -> impl IntoResponse {
     let state = raw_state.system_state.read().await;
     let mut metrics = Metrics::new();
+    // the counters come from their own endpoint, so they're left out until
+    // it's answered rather than starting from 0
+    if let Some(energy_last_update) = state.energy_last_update {
+        for (direction, lifetime, seven_days) in [
+            (
+                "production",
+                state.production_mwh_lifetime,
+                state.production_mwh_seven_days,
+            ),
+            (
+                "consumption",
+                state.consumption_mwh_lifetime,
+                state.consumption_mwh_seven_days,
+            ),
+        ] {
+            let (Some(lifetime), Some(seven_days)) = (lifetime, seven_days) else {
+                continue;
+            };
+            let mut counter = metrics.counter(
+                "energy_milliwatt_hours_total",
+                "Lifetime energy produced or consumed, as counted by the Envoy",
+            );
+            counter
+                .label("direction", direction)
+                .set_with_timestamp(lifetime, energy_last_update);
+            let mut gauge = metrics.gauge(
+                "energy_seven_days_milliwatt_hours",
+                "Energy produced or consumed over the last seven days",
+            );
+            gauge
+                .label("direction", direction)
+                .set_with_timestamp(seven_days, energy_last_update);
+        }
+    }
     if let Some(last_update) = state.last_update {
-        for (mtype, source) in &[
-            ("pv", state.pv_mw),
+        for (mtype, source) in [
+            ("pv", Some(state.pv_mw)),
             ("grid", state.grid_mw),
             ("load", state.load_mw),
             ("storage", state.storage_mw),
         ] {
+            // leave out meters this system doesn't have, rather than reporting 0
+            let Some(source) = source else {
+                continue;
+            };
             let mut gauge = metrics.gauge(
                 "power_milliwatts",
                 "Power consumed or generated by this meter",
@@ -77,8 +183,74 @@ pub async fn metrics_prom(State(raw_state): State<Arc<AppState>>) -> impl IntoRe
                 .label("type", mtype)
                 .set_with_timestamp(source, last_update);
         }
-        let battery_gauge = metrics.gauge("battery_soc_percent", "Percent of battery available");
-        battery_gauge.set_with_timestamp(state.battery_soc, last_update);
+        if let Some(battery_soc) = state.battery_soc {
+            let battery_gauge =
+                metrics.gauge("battery_soc_percent", "Percent of battery available");
+            battery_gauge.set_with_timestamp(battery_soc, last_update);
+        }
+        for meter in &state.meters {
+            for phase in &meter.phases {
+                for (name, help, value) in [
+                    (
+                        "meter_active_power_watts",
+                        "Active power measured on this phase",
+                        phase.active_power_w,
+                    ),
+                    (
+                        "meter_reactive_power_var",
+                        "Reactive power measured on this phase",
+                        phase.reactive_power_var,
+                    ),
+                    (
+                        "meter_apparent_power_va",
+                        "Apparent power measured on this phase",
+                        phase.apparent_power_va,
+                    ),
+                    (
+                        "meter_voltage_volts",
+                        "RMS voltage measured on this phase",
+                        phase.voltage_v,
+                    ),
+                    (
+                        "meter_current_amps",
+                        "RMS current measured on this phase",
+                        phase.current_a,
+                    ),
+                    (
+                        "meter_power_factor",
+                        "Power factor measured on this phase",
+                        phase.power_factor,
+                    ),
+                ] {
+                    let mut gauge = metrics.gauge(name, help);
+                    gauge
+                        .label("meter", meter.measurement_type.as_str())
+                        .label("phase", &phase.phase)
+                        .set_with_timestamp(value, meter.timestamp);
+                }
+                if let Some(frequency) = phase.frequency_hz {
+                    let mut gauge = metrics.gauge(
+                        "meter_frequency_hertz",
+                        "Line frequency measured on this phase",
+                    );
+                    gauge
+                        .label("meter", meter.measurement_type.as_str())
+                        .label("phase", &phase.phase)
+                        .set_with_timestamp(frequency, meter.timestamp);
+                }
+            }
+        }
+    }
+    drop(state);
+    for (kind, summary) in raw_state.daily_total_summaries().await {
+        let Some(delta) = summary.integrated_delta() else {
+            continue;
+        };
+        let mut gauge = metrics.gauge(
+            "energy_today_integrated_delta_milliwatt_hours",
+            "Today's energy integrated from power readings, less the Envoy's own daily counter",
+        );
+        gauge.label("series", kind.query_name()).set(delta);
     }
     let inventory = raw_state.inventory.read().await;
     let battery_cap_gauge = metrics.gauge(
@@ -86,18 +258,593 @@ pub async fn metrics_prom(State(raw_state): State<Arc<AppState>>) -> impl IntoRe
         "Total battery capacity in watt-hours",
     );
     battery_cap_gauge.set(inventory.battery_capacity);
+    for battery in &inventory.batteries {
+        for (name, help, value) in [
+            (
+                "battery_unit_soc_percent",
+                "Percent of this battery available",
+                battery.percent_full as i64,
+            ),
+            (
+                "battery_unit_capacity_wh",
+                "Capacity of this battery in watt-hours",
+                battery.capacity_wh as i64,
+            ),
+            (
+                "battery_unit_temperature_celsius",
+                "Temperature of this battery",
+                battery.temperature_c as i64,
+            ),
+            (
+                "battery_unit_led_status",
+                "LED status code of this battery",
+                battery.led_status as i64,
+            ),
+            (
+                "battery_unit_communicating",
+                "Whether this battery is communicating with the Envoy",
+                battery.communicating as i64,
+            ),
+            (
+                "battery_unit_last_report_timestamp_seconds",
+                "Time of the last report from this battery",
+                battery.last_report.timestamp(),
+            ),
+        ] {
+            let mut gauge = metrics.gauge(name, help);
+            gauge.label("serial", &battery.serial).set(value);
+        }
+        for (band, level) in [
+            ("sub_ghz", battery.comm_level_sub_ghz),
+            ("2_4_ghz", battery.comm_level_2_4_ghz),
+        ] {
+            let mut gauge = metrics.gauge(
+                "battery_unit_comm_level",
+                "Signal strength (0-5) between this battery and the Envoy",
+            );
+            gauge
+                .label("serial", &battery.serial)
+                .label("band", band)
+                .set(level);
+        }
+    }
+    if let Some(grid_state) = inventory.grid_state {
+        for candidate in GridState::ALL {
+            let mut gauge = metrics.gauge(
+                "grid_state",
+                "Whether the system is currently in this grid state",
+            );
+            gauge
+                .label("state", candidate.as_str())
+                .set((candidate == grid_state) as i64);
+        }
+    }
+    if let Some(controller) = inventory.system_controller.as_ref() {
+        for (relay, relay_state) in [
+            ("admin", controller.mains_admin_state),
+            ("oper", controller.mains_oper_state),
+        ] {
+            for candidate in RelayState::ALL {
+                let mut gauge = metrics.gauge(
+                    "system_controller_mains_relay_state",
+                    "Whether the system controller's mains relay is in this state",
+                );
+                gauge
+                    .label("serial", &controller.serial)
+                    .label("relay", relay)
+                    .label("state", candidate.as_str())
+                    .set((candidate == relay_state) as i64);
+            }
+        }
+        let mut bitmask_gauge = metrics.gauge(
+            "system_controller_relay_state_bitmask",
+            "Raw relay state bitmask reported by the system controller",
+        );
+        bitmask_gauge
+            .label("serial", &controller.serial)
+            .set(controller.relay_state_bitmask);
+        let mut communicating_gauge = metrics.gauge(
+            "system_controller_communicating",
+            "Whether the system controller is communicating with the Envoy",
+        );
+        communicating_gauge
+            .label("serial", &controller.serial)
+            .set(controller.communicating as i64);
+    }
+    for contact in &inventory.dry_contacts {
+        let mut gauge = metrics.gauge("dry_contact_closed", "Whether this dry contact is closed");
+        gauge
+            .label("id", &contact.id)
+            .set((contact.status == RelayState::Closed) as i64);
+    }
+    if let Some(generator) = inventory.generator.as_ref() {
+        let mut gauge = metrics.gauge("generator_info", "Generator state as reported by the Envoy");
+        gauge
+            .label(
+                "admin_state",
+                generator.admin_state.as_deref().unwrap_or(""),
+            )
+            .label("oper_state", generator.oper_state.as_deref().unwrap_or(""))
+            .label("admin_mode", generator.admin_mode.as_deref().unwrap_or(""))
+            .set(1);
+    }
+    if let Some(device) = raw_state.device_info.read().await.as_ref() {
+        let mut info_gauge = metrics.gauge("envoy_info", "Information about the Envoy gateway");
+        info_gauge
+            .label("serial", &device.serial)
+            .label("firmware", &device.firmware)
+            .label("part_number", &device.part_number)
+            .set(1);
+        if let Some(build_time) = device.build_time {
+            let build_gauge = metrics.gauge(
+                "envoy_firmware_build_timestamp_seconds",
+                "Build time of the firmware running on the Envoy",
+            );
+            build_gauge.set(build_time.timestamp());
+        }
+        let offset_gauge = metrics.gauge(
+            "envoy_clock_offset_seconds",
+            "How far ahead of this proxy's clock the Envoy's clock is",
+        );
+        offset_gauge.set(device.clock_offset_secs);
+        let reachable_gauge = metrics.gauge(
+            "envoy_info_reachable_since_timestamp_seconds",
+            "Since when the Envoy has answered every info poll from this proxy",
+        );
+        reachable_gauge.set(device.reachable_since.timestamp());
+    }
+    let inverters = raw_state.inverters.read().await;
+    for inverter in inverters.iter() {
+        let mut gauge = metrics.gauge(
+            "inverter_power_watts",
+            "Power produced by this microinverter as of its last report",
+        );
+        gauge
+            .label("serial", &inverter.serial)
+            .set_with_timestamp(inverter.last_report_watts, inverter.last_report);
+        let mut max_gauge = metrics.gauge(
+            "inverter_max_power_watts",
+            "Maximum power ever reported by this microinverter",
+        );
+        max_gauge
+            .label("serial", &inverter.serial)
+            .set(inverter.max_report_watts);
+        let mut last_report_gauge = metrics.gauge(
+            "inverter_last_report_timestamp_seconds",
+            "Time of the last report from this microinverter",
+        );
+        last_report_gauge
+            .label("serial", &inverter.serial)
+            .set(inverter.last_report.timestamp());
+    }
+    if let Some(token) = raw_state.tokens.current().await {
+        let expiry_gauge = metrics.gauge(
+            "envoy_token_expiry_timestamp_seconds",
+            "Time at which the Envoy token expires",
+        );
+        expiry_gauge.set(token.expires_at().timestamp());
+    }
+    let auth_failed_gauge = metrics.gauge(
+        "envoy_auth_failed",
+        "Whether the Envoy is currently rejecting our token",
+    );
+    auth_failed_gauge.set(raw_state.auth_failed_since.read().await.is_some() as i64);
+    let auth_stats = raw_state.auth_stats.read().await;
+    let auth_count_counter = metrics.counter(
+        "envoy_auth_sessions_total",
+        "Number of times we've established a session with the Envoy",
+    );
+    auth_count_counter.set(auth_stats.count);
+    if let Some(last_duration) = auth_stats.last_duration {
+        let auth_duration_gauge = metrics.gauge(
+            "envoy_auth_duration_seconds",
+            "How long it took to establish the most recent session with the Envoy",
+        );
+        auth_duration_gauge.set(last_duration.as_secs_f64());
+    }
+    drop(auth_stats);
+    for (task, status) in raw_state.tasks.read().await.iter() {
+        for (name, help, value) in [
+            (
+                "envoyproxy_task_runs_total",
+                "Number of times this background task has run",
+                status.runs,
+            ),
+            (
+                "envoyproxy_task_failures_total",
+                "Number of times this background task has failed",
+                status.failures,
+            ),
+        ] {
+            let mut counter = metrics.counter(name, help);
+            counter.label("task", task).set(value);
+        }
+        let mut consecutive_gauge = metrics.gauge(
+            "envoyproxy_task_consecutive_failures",
+            "Number of times in a row this background task has failed",
+        );
+        consecutive_gauge
+            .label("task", task)
+            .set(status.consecutive_failures);
+        if let Some(last_duration) = status.last_duration_secs {
+            let mut gauge = metrics.gauge(
+                "envoyproxy_task_last_duration_seconds",
+                "How long the most recent run of this background task took",
+            );
+            gauge.label("task", task).set(last_duration);
+        }
+        if let Some(last_success) = status.last_success {
+            let mut gauge = metrics.gauge(
+                "envoyproxy_task_last_success_timestamp_seconds",
+                "When this background task last succeeded",
+            );
+            gauge.label("task", task).set(last_success.timestamp());
+        }
+    }
+    let endpoints = raw_state.endpoints.read().await;
+    for (endpoint, status) in endpoints.iter() {
+        if let Some(last_success) = status.last_success {
+            let mut gauge = metrics.gauge(
+                "envoy_endpoint_last_success_timestamp_seconds",
+                "When we last successfully fetched from this Envoy endpoint",
+            );
+            gauge
+                .label("endpoint", endpoint)
+                .set(last_success.timestamp());
+        }
+    }
+    let mut body = metrics.render().to_owned();
+    body.push_str(&render_latency_histogram(&endpoints));
+    drop(endpoints);
     let mut headers = axum::http::header::HeaderMap::new();
     headers.insert(
         axum::http::header::CONTENT_TYPE,
         axum::http::HeaderValue::from_static("text/plain; version=0.0.4"),
     );
+    (axum::http::StatusCode::OK, headers, body)
+}
+
+/// Escape a label value for the text exposition format
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// promformat doesn't support histograms, so render this one by hand
+fn render_latency_histogram(endpoints: &BTreeMap<&'static str, EndpointStatus>) -> String {
+    let name = "envoyproxy_envoy_request_duration_seconds";
+    if endpoints.is_empty() {
+        return String::new();
+    }
+    let mut out = format!(
+        "# HELP {name} How long requests to this Envoy endpoint took\n# TYPE {name} histogram\n"
+    );
+    for (endpoint, status) in endpoints {
+        let endpoint = escape_label_value(endpoint);
+        let latency = &status.latency;
+        let mut cumulative = 0;
+        for (le, count) in LATENCY_BUCKETS.iter().zip(latency.buckets) {
+            cumulative += count;
+            out.push_str(&format!(
+                "{name}_bucket{{endpoint=\"{endpoint}\",le=\"{le}\"}} {cumulative}\n"
+            ));
+        }
+        out.push_str(&format!(
+            "{name}_bucket{{endpoint=\"{endpoint}\",le=\"+Inf\"}} {}\n",
+            latency.count
+        ));
+        out.push_str(&format!(
+            "{name}_sum{{endpoint=\"{endpoint}\"}} {}\n",
+            latency.sum_secs
+        ));
+        out.push_str(&format!(
+            "{name}_count{{endpoint=\"{endpoint}\"}} {}\n",
+            latency.count
+        ));
+    }
+    out
+}
+
+pub async fn refresh(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let mut headers = axum::http::header::HeaderMap::new();
+    match state.refresh.request().await {
+        RefreshOutcome::Refreshed => (
+            axum::http::StatusCode::OK,
+            headers,
+            axum::Json(HealthcheckResponse::new(true, "refreshed")),
+        ),
+        RefreshOutcome::Failed(error) => (
+            axum::http::StatusCode::BAD_GATEWAY,
+            headers,
+            axum::Json(HealthcheckResponse::new(false, error)),
+        ),
+        RefreshOutcome::TooSoon(retry_after) => {
+            let retry_after_secs = retry_after.as_secs() + 1;
+            headers.insert(
+                axum::http::header::RETRY_AFTER,
+                axum::http::HeaderValue::from(retry_after_secs),
+            );
+            (
+                axum::http::StatusCode::TOO_MANY_REQUESTS,
+                headers,
+                axum::Json(HealthcheckResponse::new(
+                    false,
+                    format!("refreshed too recently; try again in {retry_after_secs}s"),
+                )),
+            )
+        }
+    }
+}
+
+/// How often to ping WebSocket subscribers, so that idle connections aren't
+/// dropped by proxies
+const WEBSOCKET_KEEPALIVE: Duration = Duration::from_secs(30);
+
+/// Wait for the next event for a subscriber; one that's fallen too far behind
+/// gets a fresh snapshot in place of the events it missed
+async fn next_event(
+    state: &AppState,
+    rx: &mut broadcast::Receiver<StateEvent>,
+) -> Option<StateEvent> {
+    match rx.recv().await {
+        Ok(event) => Some(event),
+        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+            tracing::debug!(skipped, "event subscriber fell behind; resending snapshot");
+            Some(state.snapshot_event().await)
+        }
+        Err(broadcast::error::RecvError::Closed) => None,
+    }
+}
+
+pub async fn events_sse(
+    State(state): State<Arc<AppState>>,
+) -> Sse<impl Stream<Item = Result<sse::Event, Infallible>>> {
+    let rx = state.events.subscribe();
+    let snapshot = state.snapshot_event().await;
+    let events = futures_util::stream::unfold(
+        (state, rx, Some(snapshot)),
+        |(state, mut rx, pending)| async move {
+            let event = match pending {
+                Some(event) => event,
+                None => next_event(&state, &mut rx).await?,
+            };
+            Some((event, (state, rx, None)))
+        },
+    )
+    .map(|event| {
+        Ok(sse::Event::default()
+            .event(event.name())
+            .json_data(&event)
+            .unwrap_or_else(|error| sse::Event::default().comment(error.to_string())))
+    });
+    Sse::new(events).keep_alive(sse::KeepAlive::default())
+}
+
+pub async fn events_ws(
+    State(state): State<Arc<AppState>>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| stream_events_ws(state, socket))
+}
+
+async fn stream_events_ws(state: Arc<AppState>, mut socket: WebSocket) {
+    let mut rx = state.events.subscribe();
+    let mut pending = Some(state.snapshot_event().await);
+    let mut keepalive = tokio::time::interval(WEBSOCKET_KEEPALIVE);
+    loop {
+        let event = match pending.take() {
+            Some(event) => event,
+            None => tokio::select! {
+                event = next_event(&state, &mut rx) => match event {
+                    Some(event) => event,
+                    None => break,
+                },
+                _ = keepalive.tick() => {
+                    if socket.send(Message::Ping(Default::default())).await.is_err() {
+                        break;
+                    }
+                    continue;
+                },
+                message = socket.recv() => match message {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                },
+            },
+        };
+        let Ok(text) = serde_json::to_string(&event) else {
+            continue;
+        };
+        if socket.send(Message::Text(text.into())).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct HistoryParams {
+    /// Comma-separated; defaults to every series
+    series: Option<String>,
+    /// Comma-separated battery serial numbers to add the state of charge of
+    batteries: Option<String>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    #[serde(default)]
+    step: Step,
+    #[serde(default)]
+    agg: Aggregation,
+}
+
+#[derive(Serialize, Debug)]
+struct HistoryQueryResponse {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    step: Step,
+    agg: Aggregation,
+    series: BTreeMap<&'static str, Vec<HistoryPoint>>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    batteries: BTreeMap<String, Vec<HistoryPoint>>,
+}
+
+pub async fn history(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<HistoryParams>,
+) -> impl IntoResponse {
+    let serials: Vec<String> = params
+        .batteries
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|serial| !serial.is_empty())
+        .map(str::to_owned)
+        .collect();
+    // asking for batteries alone shouldn't drag in every other series
+    let kinds = match (params.series.as_deref(), serials.is_empty()) {
+        (None, false) => Ok(Vec::new()),
+        (series, _) => HistoryKind::parse_list(series.unwrap_or_default()),
+    };
+    let kinds = match kinds {
+        Ok(kinds) => kinds,
+        Err(error) => {
+            return (axum::http::StatusCode::BAD_REQUEST, error.to_string()).into_response();
+        }
+    };
+    let to = params.to.unwrap_or_else(Utc::now);
+    let from = params.from.unwrap_or(to - TimeDelta::days(1));
+    let series = state
+        .query_history(&kinds, from, to, params.step, params.agg)
+        .await;
+    let batteries = state
+        .query_battery_history(&serials, from, to, params.step, params.agg)
+        .await;
+    match series.and_then(|series| Ok((series, batteries?))) {
+        Ok((series, batteries)) => axum::Json(HistoryQueryResponse {
+            from,
+            to,
+            step: params.step,
+            agg: params.agg,
+            series,
+            batteries,
+        })
+        .into_response(),
+        Err(error) => (axum::http::StatusCode::BAD_REQUEST, format!("{error:#}")).into_response(),
+    }
+}
+
+/// How many chunks of an export to buffer ahead of a slow client
+const EXPORT_BUFFER: usize = 4;
+
+#[derive(Deserialize, Debug)]
+pub struct ExportParams {
+    /// Comma-separated; defaults to every series
+    series: Option<String>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    /// Defaults to every reading
+    step: Option<Step>,
+    #[serde(default)]
+    local: bool,
+}
+
+pub async fn export_csv(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ExportParams>,
+) -> impl IntoResponse {
+    stream_export(&state, params, ExportFormat::Csv)
+}
+
+pub async fn export_ndjson(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ExportParams>,
+) -> impl IntoResponse {
+    stream_export(&state, params, ExportFormat::Ndjson)
+}
+
+fn stream_export(
+    state: &AppState,
+    params: ExportParams,
+    format: ExportFormat,
+) -> axum::response::Response {
+    let series = match HistoryKind::parse_list(params.series.as_deref().unwrap_or_default()) {
+        Ok(series) => series,
+        Err(error) => {
+            return (axum::http::StatusCode::BAD_REQUEST, error.to_string()).into_response();
+        }
+    };
+    let query = ExportQuery {
+        format,
+        series,
+        from: params.from,
+        to: params.to,
+        step: params.step.unwrap_or(Step::Raw),
+        local: params.local,
+        max_gap: state.history_max_gap,
+    };
+    // a connection of our own, so that a slow client doesn't hold up writes
+    let db = match export::open_database(&state.state_path) {
+        Ok(db) => db,
+        Err(error) => {
+            return (
+                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                format!("{error:#}"),
+            )
+                .into_response();
+        }
+    };
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<String, std::io::Error>>(EXPORT_BUFFER);
+    tokio::task::spawn_blocking(move || {
+        let result = query.run(&db, |chunk| {
+            tx.blocking_send(Ok(chunk))
+                .map_err(|_| anyhow::anyhow!("client went away"))
+        });
+        if let Err(error) = result {
+            tracing::warn!(?error, "export failed");
+            let _ = tx.blocking_send(Err(std::io::Error::other(format!("{error:#}"))));
+        }
+    });
+    let chunks = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
     (
-        axum::http::StatusCode::OK,
-        headers,
-        metrics.render().to_owned(),
+        [(axum::http::header::CONTENT_TYPE, format.content_type())],
+        axum::body::Body::from_stream(chunks),
     )
+        .into_response()
+}
+
+pub async fn task_status(
+    State(state): State<Arc<AppState>>,
+) -> axum::response::Json<BTreeMap<&'static str, TaskStatus>> {
+    axum::Json(state.tasks.read().await.clone())
 }
 
 pub async fn root() -> impl IntoResponse {
     "This is a tool for monitoring Enphase Envoy-based systems.\n"
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_histogram() {
+        let mut endpoints = BTreeMap::new();
+        let mut status = EndpointStatus::default();
+        status.latency.buckets[1] = 2;
+        status.latency.buckets[3] = 1;
+        status.latency.count = 4;
+        status.latency.sum_secs = 61.5;
+        endpoints.insert("/odd \"path\"\\", status);
+        let rendered = render_latency_histogram(&endpoints);
+        let name = "envoyproxy_envoy_request_duration_seconds";
+        let labels = r#"endpoint="/odd \"path\"\\""#;
+        assert!(rendered.contains(&format!("{name}_bucket{{{labels},le=\"0.05\"}} 0\n")));
+        assert!(rendered.contains(&format!("{name}_bucket{{{labels},le=\"0.1\"}} 2\n")));
+        assert!(rendered.contains(&format!("{name}_bucket{{{labels},le=\"0.5\"}} 3\n")));
+        assert!(rendered.contains(&format!("{name}_bucket{{{labels},le=\"+Inf\"}} 4\n")));
+        assert!(rendered.contains(&format!("{name}_sum{{{labels}}} 61.5\n")));
+        assert!(rendered.contains(&format!("{name}_count{{{labels}}} 4\n")));
+    }
+}
@@ -1,15 +1,73 @@
-use clap::Parser;
+use chrono::TimeDelta;
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 use std::time::Duration;
 
+use crate::envoy_api::Dialect;
+use crate::export::ExportFormat;
+use crate::state::HistoryKind;
+use crate::time_series::Step;
+
 #[derive(Parser, Debug, Clone)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
     #[arg(short, long, default_value = "3112", env = "PORT")]
     pub port: u16,
     #[arg(long, default_value = "https://envoy.local", env = "ENVOY_URL")]
     pub envoy_url: url::Url,
     #[arg(long, env = "ENVOY_JWT")]
-    pub envoy_jwt: String,
+    pub envoy_jwt: Option<String>,
+    #[arg(
+        long,
+        env = "ENLIGHTEN_USERNAME",
+        help = "Enlighten username, used to fetch Envoy tokens automatically"
+    )]
+    pub enlighten_username: Option<String>,
+    #[arg(long, env = "ENLIGHTEN_PASSWORD", hide_env_values = true)]
+    pub enlighten_password: Option<String>,
+    #[arg(long, env = "ENVOY_SERIAL")]
+    pub envoy_serial: Option<String>,
+    #[arg(
+        long,
+        default_value = "https://enlighten.enphaseenergy.com",
+        env = "ENLIGHTEN_URL"
+    )]
+    pub enlighten_url: url::Url,
+    #[arg(
+        long,
+        default_value = "https://entrez.enphaseenergy.com",
+        env = "ENTREZ_URL"
+    )]
+    pub entrez_url: url::Url,
+    #[arg(
+        long,
+        value_enum,
+        env = "ENVOY_DIALECT",
+        help = "Which generation of the Envoy API to use; detected from the firmware version if unset"
+    )]
+    pub envoy_dialect: Option<Dialect>,
+    #[arg(
+        long,
+        default_value = "envoy",
+        env = "ENVOY_LEGACY_USERNAME",
+        help = "Username for digest authentication with legacy firmware"
+    )]
+    pub legacy_username: String,
+    #[arg(
+        long,
+        env = "ENVOY_LEGACY_PASSWORD",
+        hide_env_values = true,
+        help = "Password for digest authentication with legacy firmware; defaults to the last six digits of the serial number"
+    )]
+    pub legacy_password: Option<String>,
+    #[arg(
+        long,
+        env = "ENVOY_SESSION_AUTH",
+        help = "Authenticate once per session with a cookie instead of sending the token on every request"
+    )]
+    pub session_auth: bool,
     #[arg(
         long,
         default_value = "60",
@@ -22,8 +80,196 @@ pub struct Args {
         help = "Interval to collect system inventory, in seconds"
     )]
     pub inventory_poll_interval_secs: u32,
+    #[arg(
+        long,
+        env = "ADAPTIVE_POLLING",
+        help = "Poll the system state more often while readings are changing quickly, and less often at night"
+    )]
+    pub adaptive_polling: bool,
+    #[arg(
+        long,
+        env = "ENVOY_LIVE_STREAM",
+        help = "Ask the Envoy to stream live power readings, and read them every few seconds"
+    )]
+    pub live_stream: bool,
+    #[arg(
+        long,
+        default_value = "1",
+        help = "Interval to read the live stream, in seconds"
+    )]
+    pub stream_read_interval_secs: u32,
+    #[arg(
+        long,
+        default_value = "300",
+        help = "Interval to re-enable the live stream so that it doesn't time out, in seconds"
+    )]
+    pub stream_keepalive_secs: u32,
+    #[arg(
+        long,
+        default_value = "60",
+        help = "Average live stream readings over this many seconds before recording them in history"
+    )]
+    pub stream_downsample_secs: u32,
+    #[arg(
+        long,
+        default_value = "10",
+        help = "Shortest interval to poll the system state with adaptive polling, in seconds"
+    )]
+    pub min_poll_interval_secs: u32,
+    #[arg(
+        long,
+        default_value = "300",
+        help = "Longest interval to poll the system state with adaptive polling, in seconds"
+    )]
+    pub max_poll_interval_secs: u32,
+    #[arg(
+        long,
+        default_value = "500000",
+        help = "Change in PV or load power between polls, in milliwatts, that makes adaptive polling use its shortest interval"
+    )]
+    pub poll_change_threshold_mw: u32,
+    #[arg(
+        long,
+        env = "LATITUDE",
+        allow_negative_numbers = true,
+        requires = "longitude",
+        help = "Latitude of the system, used to poll less often at night with adaptive polling"
+    )]
+    pub latitude: Option<f64>,
+    #[arg(
+        long,
+        env = "LONGITUDE",
+        allow_negative_numbers = true,
+        requires = "latitude",
+        help = "Longitude of the system (east is positive)"
+    )]
+    pub longitude: Option<f64>,
+    #[arg(
+        long,
+        default_value = "300",
+        help = "Interval to collect per-inverter production, in seconds"
+    )]
+    pub inverter_poll_interval_secs: u32,
+    #[arg(
+        long,
+        default_value = "300",
+        help = "Interval to collect battery state of charge, in seconds"
+    )]
+    pub battery_poll_interval_secs: u32,
+    #[arg(
+        long,
+        default_value = "10",
+        help = "Minimum interval between on-demand refreshes of the system state, in seconds"
+    )]
+    pub refresh_min_interval_secs: u32,
     #[arg(long, env = "STATE_PATH", help = "Path to persist history")]
     pub state_path: PathBuf,
+    #[arg(
+        long,
+        default_value = "900",
+        help = "Longest a reading counts for in history statistics when no newer one follows it, in seconds; any longer is treated as missing data"
+    )]
+    pub history_max_gap_secs: u32,
+    #[arg(
+        long,
+        default_value = "14",
+        help = "Days to keep every reading for; 0 keeps them forever"
+    )]
+    pub raw_retention_days: u32,
+    #[arg(
+        long,
+        default_value = "90",
+        help = "Days to keep 5-minute rollups of history for; 0 keeps them forever"
+    )]
+    pub five_minute_retention_days: u32,
+    #[arg(
+        long,
+        default_value = "730",
+        help = "Days to keep hourly rollups of history for; 0 keeps them forever"
+    )]
+    pub hourly_retention_days: u32,
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Days to keep daily rollups of history for, at least 31; 0 keeps them forever"
+    )]
+    pub daily_retention_days: u32,
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Days to keep monthly rollups of history for; 0 keeps them forever"
+    )]
+    pub monthly_retention_days: u32,
+    #[arg(
+        long,
+        default_value = "604800",
+        help = "Report unhealthy when the Envoy token expires within this many seconds"
+    )]
+    pub token_expiry_warning_secs: u32,
+    #[arg(
+        long,
+        default_value = "2592000",
+        help = "Fetch a new Envoy token from Enlighten when the current one expires within this many seconds"
+    )]
+    pub token_refresh_before_secs: u32,
+    #[arg(
+        long,
+        default_value = "5",
+        help = "How long to wait before retrying a failed task, in seconds; doubles with each consecutive failure"
+    )]
+    pub retry_initial_backoff_secs: u32,
+    #[arg(
+        long,
+        default_value = "300",
+        help = "Longest to wait between retries of a failed task, in seconds"
+    )]
+    pub retry_max_backoff_secs: u32,
+    #[arg(
+        long,
+        default_value = "10",
+        help = "Stop retrying quickly after this many consecutive failures of a task"
+    )]
+    pub circuit_breaker_threshold: u32,
+    #[arg(
+        long,
+        default_value = "900",
+        help = "Interval to retry a task which has hit the circuit breaker, in seconds"
+    )]
+    pub circuit_breaker_cooldown_secs: u32,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Write history from the database at `--state-path` to stdout, without
+    /// starting the server
+    Export(ExportArgs),
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ExportArgs {
+    #[arg(long, value_enum, default_value = "csv")]
+    pub format: ExportFormat,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Series to export (pv, grid, load, storage, soc, production_today, consumption_today); defaults to all of them"
+    )]
+    pub series: Vec<HistoryKind>,
+    #[arg(long, help = "Only export history from this time on (RFC 3339)")]
+    pub from: Option<DateTime<Utc>>,
+    #[arg(long, help = "Only export history up to this time (RFC 3339)")]
+    pub to: Option<DateTime<Utc>>,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "raw",
+        help = "Average readings over this step"
+    )]
+    pub step: Step,
+    #[arg(long, help = "Write timestamps in the local timezone rather than UTC")]
+    pub local: bool,
+    #[arg(short, long, help = "Write to this file instead of stdout")]
+    pub output: Option<PathBuf>,
 }
 
 impl Args {
@@ -31,7 +277,150 @@ impl Args {
         Duration::from_secs(self.poll_interval_secs as u64)
     }
 
+    pub fn min_poll_interval(&self) -> Duration {
+        Duration::from_secs(self.min_poll_interval_secs as u64)
+    }
+
+    pub fn max_poll_interval(&self) -> Duration {
+        Duration::from_secs(self.max_poll_interval_secs as u64)
+    }
+
+    pub fn stream_read_interval(&self) -> Duration {
+        Duration::from_secs(self.stream_read_interval_secs as u64)
+    }
+
+    pub fn stream_keepalive(&self) -> Duration {
+        Duration::from_secs(self.stream_keepalive_secs as u64)
+    }
+
+    pub fn stream_downsample(&self) -> TimeDelta {
+        TimeDelta::seconds(self.stream_downsample_secs as i64)
+    }
+
     pub fn inventory_poll_interval(&self) -> Duration {
         Duration::from_secs(self.inventory_poll_interval_secs as u64)
     }
+
+    pub fn inverter_poll_interval(&self) -> Duration {
+        Duration::from_secs(self.inverter_poll_interval_secs as u64)
+    }
+
+    pub fn battery_poll_interval(&self) -> Duration {
+        Duration::from_secs(self.battery_poll_interval_secs as u64)
+    }
+
+    pub fn refresh_min_interval(&self) -> Duration {
+        Duration::from_secs(self.refresh_min_interval_secs as u64)
+    }
+
+    pub fn token_expiry_warning(&self) -> TimeDelta {
+        TimeDelta::seconds(self.token_expiry_warning_secs as i64)
+    }
+
+    pub fn token_refresh_before(&self) -> TimeDelta {
+        TimeDelta::seconds(self.token_refresh_before_secs as i64)
+    }
+
+    pub fn history_max_gap(&self) -> TimeDelta {
+        TimeDelta::seconds(self.history_max_gap_secs as i64)
+    }
+
+    pub fn raw_retention(&self) -> Option<TimeDelta> {
+        retention_days(self.raw_retention_days)
+    }
+
+    pub fn five_minute_retention(&self) -> Option<TimeDelta> {
+        retention_days(self.five_minute_retention_days)
+    }
+
+    pub fn hourly_retention(&self) -> Option<TimeDelta> {
+        retention_days(self.hourly_retention_days)
+    }
+
+    pub fn daily_retention(&self) -> Option<TimeDelta> {
+        retention_days(self.daily_retention_days)
+    }
+
+    pub fn monthly_retention(&self) -> Option<TimeDelta> {
+        retention_days(self.monthly_retention_days)
+    }
+
+    /// Each resolution of history is built from the one below it, which has
+    /// to be kept for at least a whole bucket of the coarser one; otherwise its
+    /// buckets would be rebuilt from only part of the data
+    pub fn validate_retention(&self) -> anyhow::Result<()> {
+        let resolutions = [
+            (
+                "--raw-retention-days",
+                self.raw_retention(),
+                "5-minute",
+                TimeDelta::minutes(5),
+            ),
+            (
+                "--five-minute-retention-days",
+                self.five_minute_retention(),
+                "hourly",
+                TimeDelta::hours(1),
+            ),
+            (
+                "--hourly-retention-days",
+                self.hourly_retention(),
+                "daily",
+                TimeDelta::days(1),
+            ),
+            (
+                "--daily-retention-days",
+                self.daily_retention(),
+                "monthly",
+                TimeDelta::days(31),
+            ),
+        ];
+        for (flag, keep, coarser, bucket) in resolutions {
+            if keep.is_some_and(|keep| keep < bucket) {
+                anyhow::bail!(
+                    "{flag} must be 0 or at least {} to build {coarser} rollups from",
+                    bucket.num_days().max(1)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn retry_initial_backoff(&self) -> Duration {
+        Duration::from_secs(self.retry_initial_backoff_secs as u64)
+    }
+
+    pub fn retry_max_backoff(&self) -> Duration {
+        Duration::from_secs(self.retry_max_backoff_secs as u64)
+    }
+
+    pub fn circuit_breaker_cooldown(&self) -> Duration {
+        Duration::from_secs(self.circuit_breaker_cooldown_secs as u64)
+    }
+}
+
+fn retention_days(days: u32) -> Option<TimeDelta> {
+    (days > 0).then(|| TimeDelta::days(days as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retention_must_cover_coarser_buckets() {
+        let parse = |days: &str| {
+            Args::try_parse_from([
+                "envoyproxy",
+                "--state-path",
+                "state.db",
+                "--daily-retention-days",
+                days,
+            ])
+            .unwrap()
+        };
+        assert!(parse("0").validate_retention().is_ok());
+        assert!(parse("31").validate_retention().is_ok());
+        assert!(parse("7").validate_retention().is_err());
+    }
 }
@@ -1,10 +1,18 @@
 use anyhow::Result;
 use chrono::serde::ts_seconds;
 use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
 use url::Url;
 
-use crate::state::{Inventory, SystemState};
+use crate::state::{
+    Battery, DeviceInfo, DeviceInfoPackage, DryContact, Generator, Inventory, Inverter,
+    LiveReading, MeterReading, PhaseReading, SystemController, SystemState,
+};
+use crate::token::EnvoyToken;
+
+pub mod legacy;
 
 #[derive(Deserialize, Debug)]
 pub struct MeterDetails {
@@ -14,22 +22,126 @@ pub struct MeterDetails {
 
 #[derive(Deserialize, Debug)]
 pub struct LivestatusMetersResponse {
-    pub soc: u32,
     #[serde(with = "ts_seconds")]
     pub last_update: DateTime<Utc>,
     pub pv: MeterDetails,
-    pub storage: MeterDetails,
-    pub grid: MeterDetails,
-    pub load: MeterDetails,
+    // systems without batteries or consumption CTs leave these out
+    pub soc: Option<u32>,
+    pub storage: Option<MeterDetails>,
+    pub grid: Option<MeterDetails>,
+    pub load: Option<MeterDetails>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct LivestatusResponse {
     pub meters: LivestatusMetersResponse,
+    pub connection: Option<LivestatusConnection>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LivestatusConnection {
+    pub sc_stream: String,
+}
+
+impl LivestatusResponse {
+    /// Whether the Envoy is currently updating this every second or so
+    pub fn is_streaming(&self) -> bool {
+        self.connection
+            .as_ref()
+            .is_some_and(|c| c.sc_stream == "enabled")
+    }
+
+    pub fn reading(&self) -> LiveReading {
+        LiveReading {
+            timestamp: self.meters.last_update,
+            pv_mw: self.meters.pv.aggregate_mw,
+            grid_mw: self.meters.grid.as_ref().map(|m| m.aggregate_mw),
+            storage_mw: self.meters.storage.as_ref().map(|m| m.aggregate_mw),
+            load_mw: self.meters.load.as_ref().map(|m| m.aggregate_mw),
+            battery_soc: self.meters.soc,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MeterType {
+    Production,
+    NetConsumption,
+    TotalConsumption,
+}
+
+impl MeterType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Production => "production",
+            Self::NetConsumption => "net-consumption",
+            Self::TotalConsumption => "total-consumption",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MeterConfig {
+    pub eid: u64,
+    pub state: String,
+    pub measurement_type: MeterType,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MeterChannelReading {
+    pub active_power: f64,
+    pub reactive_power: f64,
+    pub apparent_power: f64,
+    pub voltage: f64,
+    pub current: f64,
+    pub freq: f64,
+    pub pwr_factor: f64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MeterReadingsRow {
+    pub eid: u64,
+    #[serde(with = "ts_seconds")]
+    pub timestamp: DateTime<Utc>,
+    #[serde(default)]
+    pub channels: Vec<MeterChannelReading>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InfoDevice {
+    pub sn: String,
+    pub pn: String,
+    pub software: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InfoPackage {
+    #[serde(rename = "@pname")]
+    pub name: String,
+    pub pn: String,
+    pub version: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InfoBuildInfo {
+    #[serde(with = "ts_seconds")]
+    pub build_time_gmt: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InfoResponse {
+    #[serde(with = "ts_seconds")]
+    pub time: DateTime<Utc>,
+    pub device: InfoDevice,
+    #[serde(rename = "package", default)]
+    pub packages: Vec<InfoPackage>,
+    pub build_info: Option<InfoBuildInfo>,
 }
 
 #[derive(Deserialize, Debug)]
-#[allow(dead_code)]
 pub struct EnergyAggregate {
     #[serde(rename = "wattHoursToday")]
     pub watt_hours_today: i64,
@@ -41,31 +153,121 @@ pub struct EnergyAggregate {
     pub watts_now: i64,
 }
 
+/// The Envoy reports missing meters as an empty object rather than leaving
+/// them out
+fn present_aggregate<'de, D: serde::Deserializer<'de>>(
+    d: D,
+) -> Result<Option<EnergyAggregate>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum MaybeAggregate {
+        Present(EnergyAggregate),
+        Absent(serde::de::IgnoredAny),
+    }
+    Ok(match MaybeAggregate::deserialize(d)? {
+        MaybeAggregate::Present(aggregate) => Some(aggregate),
+        MaybeAggregate::Absent(_) => None,
+    })
+}
+
 #[derive(Deserialize, Debug)]
 pub struct EnergyProductionResponse {
-    #[serde(rename = "eim")]
-    pub envoy: EnergyAggregate,
+    #[serde(rename = "eim", default, deserialize_with = "present_aggregate")]
+    pub envoy: Option<EnergyAggregate>,
+    /// Totals accumulated from microinverter reports, for systems without a
+    /// production CT
+    #[serde(default, deserialize_with = "present_aggregate")]
+    pub pcu: Option<EnergyAggregate>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct EnergyConsumptionResponse {
-    #[serde(rename = "eim")]
-    pub envoy: EnergyAggregate,
+    #[serde(rename = "eim", default, deserialize_with = "present_aggregate")]
+    pub envoy: Option<EnergyAggregate>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct EnergyResponse {
     pub production: EnergyProductionResponse,
-    pub consumption: EnergyConsumptionResponse,
+    pub consumption: Option<EnergyConsumptionResponse>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct EnchargeDevice {
+    pub serial_num: String,
     pub encharge_capacity: u32,
+    #[serde(rename = "percentFull")]
+    pub percent_full: u32,
+    #[serde(default)]
+    pub temperature: i32,
+    #[serde(default)]
+    pub led_status: u32,
+    #[serde(default)]
+    pub admin_state_str: String,
+    #[serde(default)]
+    pub device_status: Vec<String>,
+    #[serde(default)]
+    pub communicating: bool,
+    #[serde(default)]
+    pub comm_level_sub_ghz: u32,
+    #[serde(default)]
+    pub comm_level_2_4_ghz: u32,
+    #[serde(with = "ts_seconds")]
+    pub last_rpt_date: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EnpowerDevice {
+    pub serial_num: String,
+    #[serde(default)]
+    pub admin_state_str: String,
+    pub mains_admin_state: RelayState,
+    pub mains_oper_state: RelayState,
+    #[serde(rename = "Enpwr_grid_mode")]
+    pub grid_mode: Option<GridState>,
+    #[serde(rename = "Enpwr_relay_state_bm", default)]
+    pub relay_state_bitmask: u32,
+    #[serde(default)]
+    pub communicating: bool,
+    #[serde(with = "ts_seconds")]
+    pub last_rpt_date: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RelayState {
+    Open,
+    Closed,
+}
+
+impl RelayState {
+    pub const ALL: [Self; 2] = [Self::Open, Self::Closed];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Open => "open",
+            Self::Closed => "closed",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DryContactsResponse {
+    pub dry_contacts: Vec<DryContactRow>,
 }
 
 #[derive(Deserialize, Debug)]
-pub struct EnpowerDevice {}
+pub struct DryContactRow {
+    pub id: String,
+    pub status: RelayState,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GeneratorResponse {
+    pub admin_state: Option<String>,
+    pub oper_state: Option<String>,
+    pub admin_mode: Option<String>,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(clippy::enum_variant_names)]
@@ -80,11 +282,39 @@ pub enum GridState {
     MultiModeOffGrid,
 }
 
+impl GridState {
+    pub const ALL: [Self; 4] = [
+        Self::OnGrid,
+        Self::OffGrid,
+        Self::MultiModeOnGrid,
+        Self::MultiModeOffGrid,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::OnGrid => "on-grid",
+            Self::OffGrid => "off-grid",
+            Self::MultiModeOnGrid => "multimode-ongrid",
+            Self::MultiModeOffGrid => "multimode-offgrid",
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct CollarDevice {
     pub grid_state: GridState,
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct InverterProductionRow {
+    pub serial_number: String,
+    #[serde(with = "ts_seconds")]
+    pub last_report_date: DateTime<Utc>,
+    pub last_report_watts: i64,
+    pub max_report_watts: i64,
+}
+
 #[derive(Deserialize)]
 #[serde(tag = "type", content = "devices")]
 pub enum InventoryDeviceRow {
@@ -110,24 +340,258 @@ impl InventoryDeviceRow {
     }
 }
 
-pub async fn fetch_inventory(
+/// Which generation of the Envoy's local API we're talking to
+#[derive(clap::ValueEnum, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Dialect {
+    /// Firmware 7.x and later, which serves token-authenticated `/ivp/` APIs
+    Modern,
+    /// Older firmware, which serves `/production.json` and digest-authenticated
+    /// `/api/v1/` APIs
+    Legacy,
+}
+
+impl Dialect {
+    pub fn from_firmware(firmware: &str) -> Self {
+        // versions look like "D7.6.175" or "R4.10.35"
+        let major = firmware
+            .trim_start_matches(|c: char| !c.is_ascii_digit())
+            .split('.')
+            .next()
+            .and_then(|m| m.parse::<u32>().ok());
+        match major {
+            Some(major) if major < 7 => Self::Legacy,
+            _ => Self::Modern,
+        }
+    }
+
+    pub async fn fetch_state(
+        self,
+        base_url: &Url,
+        auth: &EnvoyAuth,
+        client: &reqwest::Client,
+        previous: &SystemState,
+    ) -> StateFetch {
+        match self {
+            Self::Modern => fetch_state(base_url, auth, client, previous).await,
+            Self::Legacy => legacy::fetch_state(base_url, auth, client, previous).await,
+        }
+    }
+
+    /// Where `fetch_inventory` gets the inventory from
+    pub fn inventory_endpoint(self) -> &'static str {
+        match self {
+            Self::Modern => "/ivp/ensemble/inventory",
+            Self::Legacy => "/inventory.json",
+        }
+    }
+
+    pub async fn fetch_inventory(
+        self,
+        base_url: &Url,
+        auth: &EnvoyAuth,
+        client: &reqwest::Client,
+    ) -> Result<Inventory> {
+        match self {
+            Self::Modern => fetch_inventory(base_url, auth, client).await,
+            Self::Legacy => legacy::fetch_inventory(base_url, auth, client).await,
+        }
+    }
+}
+
+/// How a single endpoint fared while fetching state
+#[derive(Debug)]
+pub struct EndpointOutcome {
+    pub endpoint: &'static str,
+    pub result: Result<()>,
+    pub duration: Duration,
+}
+
+/// System state assembled from whichever endpoints answered; anything we
+/// couldn't fetch keeps its previous value
+#[derive(Debug)]
+pub struct StateFetch {
+    pub state: SystemState,
+    pub endpoints: Vec<EndpointOutcome>,
+    /// Whether the daily energy counters in `state` came from this fetch,
+    /// rather than being left over from the previous one
+    pub counters_fetched: bool,
+}
+
+impl StateFetch {
+    pub(crate) fn new(previous: &SystemState) -> Self {
+        Self {
+            state: previous.clone(),
+            endpoints: Vec::new(),
+            counters_fetched: false,
+        }
+    }
+
+    /// Apply the response from one endpoint, if we got one, returning whether
+    /// we did
+    pub(crate) fn apply<T>(
+        &mut self,
+        endpoint: &'static str,
+        (result, duration): (Result<T>, Duration),
+        f: impl FnOnce(&mut SystemState, T) -> Result<()>,
+    ) -> bool {
+        let result = result.and_then(|resp| f(&mut self.state, resp));
+        if let Err(error) = &result {
+            tracing::warn!(endpoint, ?error, "unable to fetch from envoy");
+        }
+        let applied = result.is_ok();
+        self.endpoints.push(EndpointOutcome {
+            endpoint,
+            result,
+            duration,
+        });
+        applied
+    }
+
+    /// Only fail outright if nothing succeeded, so that a rejected token
+    /// still gets retried
+    pub fn into_result(self) -> Result<Self> {
+        if self.endpoints.iter().any(|outcome| outcome.result.is_ok()) {
+            return Ok(self);
+        }
+        match self
+            .endpoints
+            .into_iter()
+            .find_map(|outcome| outcome.result.err())
+        {
+            Some(error) => Err(error),
+            None => anyhow::bail!("no endpoints were fetched"),
+        }
+    }
+}
+
+/// Run a request to the Envoy, noting how long it took
+pub(crate) async fn timed<T>(request: impl Future<Output = Result<T>>) -> (Result<T>, Duration) {
+    let started = Instant::now();
+    let result = request.await;
+    (result, started.elapsed())
+}
+
+/// How we prove to the Envoy that we're allowed to talk to it
+#[derive(Debug, Clone)]
+pub enum EnvoyAuth {
+    /// Send the token along with every request
+    Bearer(EnvoyToken),
+    /// Rely on the session cookie set by `check_jwt`
+    Session,
+    /// Answer HTTP digest challenges, as legacy firmware issues
+    Digest { username: String, password: String },
+}
+
+impl EnvoyAuth {
+    async fn get(&self, url: Url, client: &reqwest::Client) -> Result<reqwest::Response> {
+        match self {
+            Self::Bearer(token) => Ok(client.get(url).bearer_auth(token.as_str()).send().await?),
+            Self::Session => Ok(client.get(url).send().await?),
+            Self::Digest { username, password } => {
+                // legacy firmware serves some endpoints without authentication,
+                // so only bother with digest auth if we're challenged
+                let response = client.get(url.clone()).send().await?;
+                if response.status() != reqwest::StatusCode::UNAUTHORIZED {
+                    return Ok(response);
+                }
+                let Some(challenge) = response.headers().get(reqwest::header::WWW_AUTHENTICATE)
+                else {
+                    return Ok(response);
+                };
+                let mut prompt = digest_auth::parse(challenge.to_str()?)?;
+                let context = digest_auth::AuthContext::new(
+                    username.as_str(),
+                    password.as_str(),
+                    &url[url::Position::BeforePath..],
+                );
+                let answer = prompt.respond(&context)?;
+                Ok(client
+                    .get(url)
+                    .header(reqwest::header::AUTHORIZATION, answer.to_header_string())
+                    .send()
+                    .await?)
+            }
+        }
+    }
+
+    async fn post_json<B: Serialize>(
+        &self,
+        url: Url,
+        body: &B,
+        client: &reqwest::Client,
+    ) -> Result<reqwest::Response> {
+        match self {
+            Self::Bearer(token) => Ok(client
+                .post(url)
+                .bearer_auth(token.as_str())
+                .json(body)
+                .send()
+                .await?),
+            Self::Session => Ok(client.post(url).json(body).send().await?),
+            Self::Digest { .. } => anyhow::bail!("legacy firmware doesn't accept JSON requests"),
+        }
+    }
+}
+
+/// Exchange a token for a session cookie, which the client's cookie jar will
+/// hang on to for subsequent requests
+pub async fn check_jwt(base_url: &Url, token: &EnvoyToken, client: &reqwest::Client) -> Result<()> {
+    let mut url = base_url.clone();
+    url.set_path("/auth/check_jwt");
+    tracing::trace!(?url, "fetching");
+    client
+        .get(url)
+        .bearer_auth(token.as_str())
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+pub(crate) async fn fetch_json<T: DeserializeOwned>(
     base_url: &Url,
-    envoy_jwt: &str,
+    path: &str,
+    auth: &EnvoyAuth,
     client: &reqwest::Client,
-) -> Result<Inventory> {
-    let mut inventory_url = base_url.clone();
-    inventory_url.set_path("/ivp/ensemble/inventory");
-    tracing::trace!(url=?inventory_url, "fetching");
-    let inventory_resp: Vec<InventoryDeviceRow> = client
-        .get(inventory_url)
-        .bearer_auth(envoy_jwt)
-        .send()
+) -> Result<T> {
+    let url = base_url.join(path)?;
+    tracing::trace!(?url, "fetching");
+    let resp = auth
+        .get(url, client)
         .await?
+        .error_for_status()?
         .json()
         .await?;
+    Ok(resp)
+}
+
+fn status_of(error: &anyhow::Error) -> Option<reqwest::StatusCode> {
+    error
+        .downcast_ref::<reqwest::Error>()
+        .and_then(|e| e.status())
+}
+
+/// Whether this error came from the Envoy rejecting our token
+pub fn is_unauthorized(error: &anyhow::Error) -> bool {
+    status_of(error) == Some(reqwest::StatusCode::UNAUTHORIZED)
+}
+
+/// Whether this error came from the Envoy not serving an endpoint at all
+pub fn is_not_found(error: &anyhow::Error) -> bool {
+    status_of(error) == Some(reqwest::StatusCode::NOT_FOUND)
+}
+
+pub async fn fetch_inventory(
+    base_url: &Url,
+    auth: &EnvoyAuth,
+    client: &reqwest::Client,
+) -> Result<Inventory> {
+    let inventory_resp: Vec<InventoryDeviceRow> =
+        fetch_json(base_url, "/ivp/ensemble/inventory", auth, client).await?;
     tracing::trace!(response = ?inventory_resp.iter().map(|r| r.devices()).collect::<Vec<_>>(), "fetched inventory");
 
-    let new_inventory = Inventory {
+    let mut new_inventory = Inventory {
         num_batteries: inventory_resp
             .iter()
             .map(|row| match row {
@@ -145,52 +609,519 @@ pub async fn fetch_inventory(
                 _ => 0,
             })
             .sum(),
+        batteries: inventory_resp
+            .iter()
+            .flat_map(|row| match row {
+                InventoryDeviceRow::Encharge(devices) => devices.as_slice(),
+                _ => &[],
+            })
+            .map(|device| Battery {
+                serial: device.serial_num.clone(),
+                capacity_wh: device.encharge_capacity,
+                percent_full: device.percent_full,
+                temperature_c: device.temperature,
+                led_status: device.led_status,
+                operating_state: device.admin_state_str.clone(),
+                device_status: device.device_status.clone(),
+                communicating: device.communicating,
+                comm_level_sub_ghz: device.comm_level_sub_ghz,
+                comm_level_2_4_ghz: device.comm_level_2_4_ghz,
+                last_report: device.last_rpt_date,
+            })
+            .collect(),
         grid_state: inventory_resp.iter().find_map(|row| match row {
             InventoryDeviceRow::Collar(devices) => devices.first().map(|s| s.grid_state),
             _ => None,
         }),
+        system_controller: inventory_resp.iter().find_map(|row| match row {
+            InventoryDeviceRow::Enpower(devices) => {
+                devices.first().map(|device| SystemController {
+                    serial: device.serial_num.clone(),
+                    operating_state: device.admin_state_str.clone(),
+                    mains_admin_state: device.mains_admin_state,
+                    mains_oper_state: device.mains_oper_state,
+                    grid_mode: device.grid_mode,
+                    relay_state_bitmask: device.relay_state_bitmask,
+                    communicating: device.communicating,
+                    last_report: device.last_rpt_date,
+                })
+            }
+            _ => None,
+        }),
+        dry_contacts: Vec::new(),
+        generator: None,
     };
+
+    // dry contacts and generators hang off of the system controller; systems
+    // without one don't serve these endpoints at all
+    if new_inventory.system_controller.is_some() {
+        match fetch_json::<DryContactsResponse>(
+            base_url,
+            "/ivp/ensemble/dry_contacts",
+            auth,
+            client,
+        )
+        .await
+        {
+            Ok(resp) => {
+                tracing::trace!(response = ?resp, "fetched dry contacts");
+                new_inventory.dry_contacts = resp
+                    .dry_contacts
+                    .into_iter()
+                    .map(|row| DryContact {
+                        id: row.id,
+                        status: row.status,
+                    })
+                    .collect();
+            }
+            Err(error) => tracing::debug!(?error, "unable to fetch dry contacts"),
+        }
+        match fetch_json::<GeneratorResponse>(base_url, "/ivp/ensemble/generator", auth, client)
+            .await
+        {
+            Ok(resp) => {
+                tracing::trace!(response = ?resp, "fetched generator");
+                new_inventory.generator = Some(Generator {
+                    admin_state: resp.admin_state,
+                    oper_state: resp.oper_state,
+                    admin_mode: resp.admin_mode,
+                });
+            }
+            Err(error) => tracing::debug!(?error, "unable to fetch generator"),
+        }
+    }
     Ok(new_inventory)
 }
 
+async fn fetch_meter_readings(
+    base_url: &Url,
+    auth: &EnvoyAuth,
+    client: &reqwest::Client,
+) -> Result<Vec<MeterReading>> {
+    // systems without CTs don't serve these at all, which isn't an error
+    let meters_resp: Vec<MeterConfig> =
+        match fetch_json(base_url, "/ivp/meters", auth, client).await {
+            Err(error) if is_not_found(&error) => {
+                tracing::debug!("envoy has no meters");
+                return Ok(Vec::new());
+            }
+            result => result?,
+        };
+    tracing::trace!(response = ?meters_resp, "fetched meters");
+    if !meters_resp.iter().any(|m| m.state == "enabled") {
+        return Ok(Vec::new());
+    }
+
+    let readings_resp: Vec<MeterReadingsRow> =
+        match fetch_json(base_url, "/ivp/meters/readings", auth, client).await {
+            Err(error) if is_not_found(&error) => {
+                tracing::debug!("envoy has no meter readings");
+                return Ok(Vec::new());
+            }
+            result => result?,
+        };
+    tracing::trace!(response = ?readings_resp, "fetched meter readings");
+
+    let readings = readings_resp
+        .into_iter()
+        .filter_map(|row| {
+            let config = meters_resp
+                .iter()
+                .find(|m| m.eid == row.eid && m.state == "enabled")?;
+            Some(MeterReading {
+                measurement_type: config.measurement_type,
+                timestamp: row.timestamp,
+                phases: row
+                    .channels
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, channel)| PhaseReading {
+                        phase: format!("L{}", i + 1),
+                        active_power_w: channel.active_power,
+                        reactive_power_var: channel.reactive_power,
+                        apparent_power_va: channel.apparent_power,
+                        voltage_v: channel.voltage,
+                        current_a: channel.current,
+                        frequency_hz: Some(channel.freq),
+                        power_factor: channel.pwr_factor,
+                    })
+                    .collect(),
+            })
+        })
+        .collect();
+    Ok(readings)
+}
+
 pub async fn fetch_state(
     base_url: &Url,
-    envoy_jwt: &str,
+    auth: &EnvoyAuth,
     client: &reqwest::Client,
-) -> Result<SystemState> {
-    let mut status_url = base_url.clone();
-    status_url.set_path("/ivp/livedata/status");
-    tracing::trace!(url = ?status_url, "fetching");
-    let status_resp: LivestatusResponse = client
-        .get(status_url)
-        .bearer_auth(envoy_jwt)
-        .send()
-        .await?
-        .json()
-        .await?;
-    tracing::trace!(response = ?status_resp, "fetched status");
-
-    let mut energy_url = base_url.clone();
-    energy_url.set_path("/ivp/pdm/energy");
-    tracing::trace!(url=?energy_url, "fetching");
-    let energy_resp: EnergyResponse = client
-        .get(energy_url)
-        .bearer_auth(envoy_jwt)
-        .send()
+    previous: &SystemState,
+) -> StateFetch {
+    let (status_result, energy_result, meters_result) = tokio::join!(
+        timed(fetch_json::<LivestatusResponse>(
+            base_url,
+            "/ivp/livedata/status",
+            auth,
+            client
+        )),
+        timed(fetch_json::<EnergyResponse>(
+            base_url,
+            "/ivp/pdm/energy",
+            auth,
+            client
+        )),
+        timed(fetch_meter_readings(base_url, auth, client)),
+    );
+
+    let mut fetch = StateFetch::new(previous);
+    fetch.apply("/ivp/livedata/status", status_result, |state, resp| {
+        tracing::trace!(response = ?resp, "fetched status");
+        resp.reading().apply_to(state);
+        Ok(())
+    });
+    fetch.counters_fetched = fetch.apply("/ivp/pdm/energy", energy_result, |state, resp| {
+        tracing::trace!(response = ?resp, "fetched energy");
+        let production = resp
+            .production
+            .envoy
+            .or(resp.production.pcu)
+            .ok_or_else(|| anyhow::anyhow!("envoy reported no production"))?;
+        let consumption = resp.consumption.and_then(|c| c.envoy);
+        state.production_mwh_today = production.watt_hours_today * 1000;
+        // the Envoy doesn't say when it last updated these
+        state.energy_last_update = Some(Utc::now());
+        state.production_mwh_seven_days = Some(production.watt_hours_seven_days * 1000);
+        state.production_mwh_lifetime = Some(production.watt_hours_lifetime * 1000);
+        state.production_mw_now = production.watts_now * 1000;
+        state.consumption_mwh_today = consumption.as_ref().map(|c| c.watt_hours_today * 1000);
+        state.consumption_mwh_seven_days =
+            consumption.as_ref().map(|c| c.watt_hours_seven_days * 1000);
+        state.consumption_mwh_lifetime = consumption.as_ref().map(|c| c.watt_hours_lifetime * 1000);
+        state.consumption_mw_now = consumption.as_ref().map(|c| c.watts_now * 1000);
+        Ok(())
+    });
+    fetch.apply("/ivp/meters/readings", meters_result, |state, meters| {
+        state.meters = meters;
+        Ok(())
+    });
+    fetch
+}
+
+/// Ask the Envoy to update `/ivp/livedata/status` every second or so; it goes
+/// back to updating slowly on its own after a while
+pub async fn enable_live_stream(
+    base_url: &Url,
+    auth: &EnvoyAuth,
+    client: &reqwest::Client,
+) -> Result<()> {
+    let url = base_url.join("/ivp/livedata/stream")?;
+    tracing::trace!(?url, "enabling live stream");
+    auth.post_json(url, &serde_json::json!({ "enable": 1 }), client)
         .await?
-        .json()
-        .await?;
-    tracing::trace!(response = ?energy_resp, "fetched energy");
-
-    let new_state = SystemState {
-        last_update: Some(status_resp.meters.last_update),
-        production_mwh_today: energy_resp.production.envoy.watt_hours_today * 1000,
-        consumption_mwh_today: energy_resp.consumption.envoy.watt_hours_today * 1000,
-        pv_mw: status_resp.meters.pv.aggregate_mw,
-        grid_mw: status_resp.meters.grid.aggregate_mw,
-        storage_mw: status_resp.meters.storage.aggregate_mw,
-        load_mw: status_resp.meters.load.aggregate_mw,
-        battery_soc: status_resp.meters.soc,
-    };
-    Ok(new_state)
+        .error_for_status()?;
+    Ok(())
+}
+
+pub async fn fetch_live_status(
+    base_url: &Url,
+    auth: &EnvoyAuth,
+    client: &reqwest::Client,
+) -> Result<LivestatusResponse> {
+    let resp: LivestatusResponse =
+        fetch_json(base_url, "/ivp/livedata/status", auth, client).await?;
+    tracing::trace!(response = ?resp, "fetched status");
+    Ok(resp)
+}
+
+pub async fn fetch_inverters(
+    base_url: &Url,
+    auth: &EnvoyAuth,
+    client: &reqwest::Client,
+) -> Result<Vec<Inverter>> {
+    let inverters_resp: Vec<InverterProductionRow> =
+        fetch_json(base_url, "/api/v1/production/inverters", auth, client).await?;
+    tracing::trace!(response = ?inverters_resp, "fetched inverters");
+
+    let inverters = inverters_resp
+        .into_iter()
+        .map(|row| Inverter {
+            serial: row.serial_number,
+            last_report: row.last_report_date,
+            last_report_watts: row.last_report_watts,
+            max_report_watts: row.max_report_watts,
+        })
+        .collect();
+    Ok(inverters)
+}
+
+pub async fn fetch_info(base_url: &Url, client: &reqwest::Client) -> Result<DeviceInfo> {
+    let mut body = None;
+    // modern firmware serves /info, but older firmware only has /info.xml
+    for path in ["/info", "/info.xml"] {
+        let mut info_url = base_url.clone();
+        info_url.set_path(path);
+        tracing::trace!(url = ?info_url, "fetching");
+        let resp = client.get(info_url).send().await?;
+        if resp.status().is_success() {
+            body = Some(resp.text().await?);
+            break;
+        }
+    }
+    let body = body.ok_or_else(|| anyhow::anyhow!("envoy did not serve /info or /info.xml"))?;
+    let fetched_at = Utc::now();
+    let info_resp: InfoResponse = quick_xml::de::from_str(&body)?;
+    tracing::trace!(response = ?info_resp, "fetched info");
+
+    Ok(DeviceInfo {
+        serial: info_resp.device.sn,
+        part_number: info_resp.device.pn,
+        firmware: info_resp.device.software,
+        build_time: info_resp.build_info.map(|b| b.build_time_gmt),
+        packages: info_resp
+            .packages
+            .into_iter()
+            .map(|p| DeviceInfoPackage {
+                name: p.name,
+                part_number: p.pn,
+                version: p.version,
+            })
+            .collect(),
+        clock: info_resp.time,
+        clock_offset_secs: (info_resp.time - fetched_at).num_seconds(),
+        reachable_since: fetched_at,
+    })
+}
+
+/// Serve canned responses at each of `routes`, as if from an Envoy, returning
+/// its base URL; anything else is a 404
+#[cfg(test)]
+pub(crate) async fn serve_fixtures(routes: &[(&'static str, &'static str)]) -> Url {
+    let mut router = axum::Router::new();
+    for (path, body) in routes {
+        let body = *body;
+        router = router.route(path, axum::routing::get(move || async move { body }));
+    }
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await });
+    url
+}
+
+#[cfg(test)]
+pub(crate) fn test_client() -> reqwest::Client {
+    reqwest::Client::builder().no_proxy().build().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIVEDATA_STATUS: &str = include_str!("../fixtures/envoy/modern/livedata_status.json");
+    const PDM_ENERGY: &str = include_str!("../fixtures/envoy/modern/pdm_energy.json");
+    const METERS: &str = include_str!("../fixtures/envoy/modern/meters.json");
+    const METERS_READINGS: &str = include_str!("../fixtures/envoy/modern/meters_readings.json");
+
+    #[tokio::test]
+    async fn modern_state() {
+        let url = serve_fixtures(&[
+            ("/ivp/livedata/status", LIVEDATA_STATUS),
+            ("/ivp/pdm/energy", PDM_ENERGY),
+            ("/ivp/meters", METERS),
+            ("/ivp/meters/readings", METERS_READINGS),
+        ])
+        .await;
+        let fetch = Dialect::Modern
+            .fetch_state(
+                &url,
+                &EnvoyAuth::Session,
+                &test_client(),
+                &SystemState::default(),
+            )
+            .await;
+        assert!(fetch.endpoints.iter().all(|outcome| outcome.result.is_ok()));
+
+        let state = fetch.state;
+        assert_eq!(state.last_update, DateTime::from_timestamp(1692806593, 0));
+        assert_eq!(state.pv_mw, 1308440);
+        assert_eq!(state.grid_mw, Some(25841));
+        assert_eq!(state.load_mw, Some(769313));
+        assert_eq!(state.storage_mw, Some(-564968));
+        assert_eq!(state.battery_soc, Some(43));
+        assert_eq!(state.production_mwh_today, 989000);
+        assert_eq!(state.production_mwh_lifetime, Some(22041230000));
+        assert_eq!(state.production_mw_now, 1308000);
+        assert_eq!(state.consumption_mwh_today, Some(4401000));
+        assert_eq!(state.consumption_mw_now, Some(769000));
+        // the disabled total-consumption meter is left out
+        let meter_types: Vec<_> = state.meters.iter().map(|m| m.measurement_type).collect();
+        assert_eq!(
+            meter_types,
+            [MeterType::Production, MeterType::NetConsumption]
+        );
+        assert_eq!(state.meters[0].phases.len(), 2);
+        assert_eq!(state.meters[0].phases[0].active_power_w, 654.22);
+        assert_eq!(state.meters[0].phases[0].frequency_hz, Some(60.0));
+    }
+
+    /// State from just the live status and energy endpoints
+    async fn fetch_power_and_energy(status: &'static str, energy: &'static str) -> SystemState {
+        let url = serve_fixtures(&[
+            ("/ivp/livedata/status", status),
+            ("/ivp/pdm/energy", energy),
+        ])
+        .await;
+        let fetch = fetch_state(
+            &url,
+            &EnvoyAuth::Session,
+            &test_client(),
+            &SystemState::default(),
+        )
+        .await;
+        // systems without CTs don't serve the meter endpoints, which is fine
+        for outcome in &fetch.endpoints {
+            assert!(outcome.result.is_ok(), "{}", outcome.endpoint);
+        }
+        assert!(fetch.counters_fetched);
+        fetch.state
+    }
+
+    #[tokio::test]
+    async fn counters_left_over_when_energy_fails() {
+        let url = serve_fixtures(&[(
+            "/ivp/livedata/status",
+            include_str!("../fixtures/envoy/modern/livedata_status.json"),
+        )])
+        .await;
+        let previous = SystemState {
+            production_mwh_today: 4000,
+            ..SystemState::default()
+        };
+        let fetch = fetch_state(&url, &EnvoyAuth::Session, &test_client(), &previous).await;
+        assert!(!fetch.counters_fetched);
+        assert_eq!(fetch.state.production_mwh_today, 4000);
+        // counters that have never been read stay unknown rather than 0
+        assert_eq!(fetch.state.production_mwh_lifetime, None);
+        assert_eq!(fetch.state.energy_last_update, None);
+    }
+
+    #[tokio::test]
+    async fn pv_only() {
+        let state = fetch_power_and_energy(
+            include_str!("../fixtures/envoy/modern/livedata_status_pv_only.json"),
+            include_str!("../fixtures/envoy/modern/pdm_energy_pv_only.json"),
+        )
+        .await;
+        assert_eq!(state.pv_mw, 1308440);
+        assert_eq!(state.grid_mw, None);
+        assert_eq!(state.load_mw, None);
+        assert_eq!(state.storage_mw, None);
+        assert_eq!(state.battery_soc, None);
+        // without a production CT, totals come from the microinverters
+        assert_eq!(state.production_mwh_today, 17512000);
+        assert_eq!(state.consumption_mwh_today, None);
+        assert_eq!(state.consumption_mwh_lifetime, None);
+        assert_eq!(state.consumption_mw_now, None);
+        assert!(state.meters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pv_and_ct() {
+        let state = fetch_power_and_energy(
+            include_str!("../fixtures/envoy/modern/livedata_status_pv_ct.json"),
+            PDM_ENERGY,
+        )
+        .await;
+        assert_eq!(state.pv_mw, 1308440);
+        assert_eq!(state.grid_mw, Some(-539132));
+        assert_eq!(state.load_mw, Some(769308));
+        assert_eq!(state.storage_mw, None);
+        assert_eq!(state.battery_soc, None);
+        assert_eq!(state.production_mwh_today, 989000);
+        assert_eq!(state.consumption_mwh_today, Some(4401000));
+        assert_eq!(state.consumption_mwh_seven_days, Some(142345000));
+    }
+
+    #[tokio::test]
+    async fn pv_ct_and_battery() {
+        let state = fetch_power_and_energy(LIVEDATA_STATUS, PDM_ENERGY).await;
+        assert_eq!(state.pv_mw, 1308440);
+        assert_eq!(state.grid_mw, Some(25841));
+        assert_eq!(state.load_mw, Some(769313));
+        assert_eq!(state.storage_mw, Some(-564968));
+        assert_eq!(state.battery_soc, Some(43));
+        assert_eq!(state.production_mwh_today, 989000);
+        assert_eq!(state.consumption_mwh_today, Some(4401000));
+    }
+
+    #[tokio::test]
+    async fn no_enabled_meters() {
+        // readings aren't served, so they'd fail if we asked for them
+        let url = serve_fixtures(&[("/ivp/meters", "[]")]).await;
+        let meters = fetch_meter_readings(&url, &EnvoyAuth::Session, &test_client())
+            .await
+            .unwrap();
+        assert!(meters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn modern_inventory() {
+        let url = serve_fixtures(&[
+            (
+                "/ivp/ensemble/inventory",
+                include_str!("../fixtures/envoy/modern/ensemble_inventory.json"),
+            ),
+            (
+                "/ivp/ensemble/dry_contacts",
+                include_str!("../fixtures/envoy/modern/dry_contacts.json"),
+            ),
+        ])
+        .await;
+        let inventory = Dialect::Modern
+            .fetch_inventory(&url, &EnvoyAuth::Session, &test_client())
+            .await
+            .unwrap();
+
+        assert_eq!(inventory.num_batteries, 1);
+        assert_eq!(inventory.battery_capacity, 3360);
+        let battery = &inventory.batteries[0];
+        assert_eq!(battery.serial, "122107012345");
+        assert_eq!(battery.percent_full, 43);
+        assert_eq!(battery.temperature_c, 29);
+        assert_eq!(battery.operating_state, "ENCHG_STATE_READY");
+        assert_eq!(battery.comm_level_2_4_ghz, 4);
+        let controller = inventory.system_controller.as_ref().unwrap();
+        assert_eq!(controller.serial, "482107012345");
+        assert_eq!(controller.mains_oper_state, RelayState::Closed);
+        assert_eq!(controller.grid_mode, Some(GridState::MultiModeOnGrid));
+        assert_eq!(controller.relay_state_bitmask, 482);
+        assert_eq!(inventory.grid_state, None);
+        assert_eq!(inventory.dry_contacts.len(), 2);
+        assert_eq!(inventory.dry_contacts[1].status, RelayState::Closed);
+        // this system has no generator endpoint
+        assert_eq!(inventory.generator, None);
+    }
+
+    #[tokio::test]
+    async fn dialect_from_info() {
+        let url =
+            serve_fixtures(&[("/info", include_str!("../fixtures/envoy/modern/info.xml"))]).await;
+        let info = fetch_info(&url, &test_client()).await.unwrap();
+        assert_eq!(info.serial, "122233445566");
+        assert_eq!(info.firmware, "D7.6.175");
+        assert_eq!(info.packages[0].version, "07.06.175");
+        assert_eq!(info.build_time, DateTime::from_timestamp(1691109421, 0));
+        assert_eq!(Dialect::from_firmware(&info.firmware), Dialect::Modern);
+
+        // older firmware only serves info.xml
+        let url = serve_fixtures(&[(
+            "/info.xml",
+            include_str!("../fixtures/envoy/legacy/info.xml"),
+        )])
+        .await;
+        let info = fetch_info(&url, &test_client()).await.unwrap();
+        assert_eq!(info.firmware, "R4.10.35");
+        assert_eq!(info.build_time, None);
+        assert_eq!(Dialect::from_firmware(&info.firmware), Dialect::Legacy);
+    }
 }
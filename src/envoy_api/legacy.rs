@@ -0,0 +1,406 @@
+//! Support for Envoy firmware older than 7.x, which predates the `/ivp/` APIs
+//! and token authentication
+
+use anyhow::Result;
+use chrono::serde::ts_seconds;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer};
+use url::Url;
+
+use super::{EnergyAggregate, EnvoyAuth, MeterType, StateFetch, fetch_json, timed};
+use crate::state::{Battery, Inventory, MeterReading, PhaseReading, SystemState};
+
+/// AC Batteries don't report their capacity, but they're all 1.2kWh
+const ACB_CAPACITY_WH: u32 = 1200;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct InvertersAggregate {
+    #[serde(with = "ts_seconds")]
+    pub reading_time: DateTime<Utc>,
+    pub w_now: f64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EimLine {
+    pub w_now: f64,
+    pub rms_current: f64,
+    pub rms_voltage: f64,
+    pub react_pwr: f64,
+    pub apprnt_pwr: f64,
+    pub pwr_factor: f64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EimAggregate {
+    pub active_count: u32,
+    pub measurement_type: MeterType,
+    #[serde(with = "ts_seconds")]
+    pub reading_time: DateTime<Utc>,
+    pub w_now: f64,
+    pub wh_lifetime: f64,
+    #[serde(default)]
+    pub wh_today: f64,
+    #[serde(default)]
+    pub wh_last_seven_days: f64,
+    #[serde(default)]
+    pub lines: Vec<EimLine>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AcbAggregate {
+    pub active_count: u32,
+    pub w_now: f64,
+    // left out when there are no AC Batteries
+    #[serde(default)]
+    pub percent_full: u32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type")]
+pub enum ProductionRow {
+    #[serde(rename = "inverters")]
+    Inverters(InvertersAggregate),
+    #[serde(rename = "eim")]
+    Eim(EimAggregate),
+    #[serde(rename = "acb")]
+    Acb(AcbAggregate),
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ProductionResponse {
+    #[serde(default)]
+    pub production: Vec<ProductionRow>,
+    #[serde(default)]
+    pub consumption: Vec<ProductionRow>,
+    #[serde(default)]
+    pub storage: Vec<ProductionRow>,
+}
+
+impl ProductionResponse {
+    fn eim(&self, measurement_type: MeterType) -> Option<&EimAggregate> {
+        self.production
+            .iter()
+            .chain(self.consumption.iter())
+            .find_map(|row| match row {
+                ProductionRow::Eim(eim)
+                    if eim.measurement_type == measurement_type && eim.active_count > 0 =>
+                {
+                    Some(eim)
+                }
+                _ => None,
+            })
+    }
+
+    fn inverters(&self) -> Option<&InvertersAggregate> {
+        self.production.iter().find_map(|row| match row {
+            ProductionRow::Inverters(inverters) => Some(inverters),
+            _ => None,
+        })
+    }
+
+    fn acb(&self) -> Option<&AcbAggregate> {
+        self.storage.iter().find_map(|row| match row {
+            ProductionRow::Acb(acb) if acb.active_count > 0 => Some(acb),
+            _ => None,
+        })
+    }
+}
+
+/// Legacy inventory reports numbers as strings
+fn lenient_timestamp<'de, D: Deserializer<'de>>(d: D) -> Result<DateTime<Utc>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Lenient {
+        Int(i64),
+        Str(String),
+    }
+    let seconds = match Lenient::deserialize(d)? {
+        Lenient::Int(i) => i,
+        Lenient::Str(s) => s.parse().map_err(serde::de::Error::custom)?,
+    };
+    DateTime::from_timestamp(seconds, 0)
+        .ok_or_else(|| serde::de::Error::custom("invalid timestamp"))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AcbDevice {
+    pub serial_num: String,
+    #[serde(rename = "percentFull", default)]
+    pub percent_full: u32,
+    #[serde(default)]
+    pub temperature: i32,
+    #[serde(default)]
+    pub device_status: Vec<String>,
+    #[serde(default)]
+    pub communicating: bool,
+    #[serde(deserialize_with = "lenient_timestamp")]
+    pub last_rpt_date: DateTime<Utc>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InventoryDeviceRow {
+    #[serde(rename = "type")]
+    pub device_type: String,
+    // we only care about batteries, so leave other device types unparsed
+    pub devices: serde_json::Value,
+}
+
+fn meter_reading(eim: &EimAggregate) -> MeterReading {
+    MeterReading {
+        measurement_type: eim.measurement_type,
+        timestamp: eim.reading_time,
+        phases: eim
+            .lines
+            .iter()
+            .enumerate()
+            .map(|(i, line)| PhaseReading {
+                phase: format!("L{}", i + 1),
+                active_power_w: line.w_now,
+                reactive_power_var: line.react_pwr,
+                apparent_power_va: line.apprnt_pwr,
+                voltage_v: line.rms_voltage,
+                current_a: line.rms_current,
+                frequency_hz: None,
+                power_factor: line.pwr_factor,
+            })
+            .collect(),
+    }
+}
+
+fn to_milli(value: f64) -> i64 {
+    (value * 1000.0).round() as i64
+}
+
+pub async fn fetch_state(
+    base_url: &Url,
+    auth: &EnvoyAuth,
+    client: &reqwest::Client,
+    previous: &SystemState,
+) -> StateFetch {
+    let mut fetch = StateFetch::new(previous);
+    let mut has_production_ct = true;
+    let production_result = timed(fetch_json::<ProductionResponse>(
+        base_url,
+        "/production.json?details=1",
+        auth,
+        client,
+    ))
+    .await;
+    let production_applied = fetch.apply("/production.json", production_result, |state, resp| {
+        tracing::trace!(response = ?resp, "fetched production");
+        let production_eim = resp.eim(MeterType::Production);
+        let total_consumption_eim = resp.eim(MeterType::TotalConsumption);
+        let net_consumption_eim = resp.eim(MeterType::NetConsumption);
+        let acb = resp.acb();
+
+        state.meters = [production_eim, total_consumption_eim, net_consumption_eim]
+            .into_iter()
+            .flatten()
+            .map(meter_reading)
+            .collect();
+
+        if let Some(eim) = production_eim {
+            state.last_update = Some(eim.reading_time);
+            state.pv_mw = to_milli(eim.w_now);
+            state.production_mw_now = to_milli(eim.w_now);
+            state.production_mwh_today = to_milli(eim.wh_today);
+            state.energy_last_update = Some(eim.reading_time);
+            state.production_mwh_seven_days = Some(to_milli(eim.wh_last_seven_days));
+            state.production_mwh_lifetime = Some(to_milli(eim.wh_lifetime));
+        } else {
+            let inverters = resp
+                .inverters()
+                .ok_or_else(|| anyhow::anyhow!("envoy reported no production"))?;
+            has_production_ct = false;
+            state.last_update = Some(inverters.reading_time);
+            state.pv_mw = to_milli(inverters.w_now);
+            state.production_mw_now = to_milli(inverters.w_now);
+        }
+
+        state.load_mw = total_consumption_eim.map(|eim| to_milli(eim.w_now));
+        state.consumption_mw_now = total_consumption_eim.map(|eim| to_milli(eim.w_now));
+        state.consumption_mwh_today = total_consumption_eim.map(|eim| to_milli(eim.wh_today));
+        state.consumption_mwh_seven_days =
+            total_consumption_eim.map(|eim| to_milli(eim.wh_last_seven_days));
+        state.consumption_mwh_lifetime = total_consumption_eim.map(|eim| to_milli(eim.wh_lifetime));
+        state.grid_mw = net_consumption_eim.map(|eim| to_milli(eim.w_now));
+        state.storage_mw = acb.map(|acb| to_milli(acb.w_now));
+        state.battery_soc = acb.map(|acb| acb.percent_full);
+        Ok(())
+    });
+
+    fetch.counters_fetched = production_applied && has_production_ct;
+
+    // without a production CT, the only daily totals are the ones the envoy
+    // accumulates from inverter reports
+    if !has_production_ct {
+        let energy_result = timed(fetch_json::<EnergyAggregate>(
+            base_url,
+            "/api/v1/production",
+            auth,
+            client,
+        ))
+        .await;
+        fetch.counters_fetched = fetch.apply("/api/v1/production", energy_result, |state, resp| {
+            tracing::trace!(response = ?resp, "fetched production energy");
+            state.production_mwh_today = resp.watt_hours_today * 1000;
+            state.energy_last_update = Some(Utc::now());
+            state.production_mwh_seven_days = Some(resp.watt_hours_seven_days * 1000);
+            state.production_mwh_lifetime = Some(resp.watt_hours_lifetime * 1000);
+            Ok(())
+        });
+    }
+
+    fetch
+}
+
+pub async fn fetch_inventory(
+    base_url: &Url,
+    auth: &EnvoyAuth,
+    client: &reqwest::Client,
+) -> Result<Inventory> {
+    let inventory_resp: Vec<InventoryDeviceRow> =
+        fetch_json(base_url, "/inventory.json", auth, client).await?;
+    tracing::trace!(response = ?inventory_resp, "fetched inventory");
+
+    let mut acb_devices: Vec<AcbDevice> = Vec::new();
+    for row in inventory_resp {
+        if row.device_type == "ACB" {
+            acb_devices.extend(serde_json::from_value::<Vec<AcbDevice>>(row.devices)?);
+        }
+    }
+
+    let batteries: Vec<Battery> = acb_devices
+        .iter()
+        .map(|device| Battery {
+            serial: device.serial_num.clone(),
+            capacity_wh: ACB_CAPACITY_WH,
+            percent_full: device.percent_full,
+            temperature_c: device.temperature,
+            led_status: 0,
+            operating_state: String::new(),
+            device_status: device.device_status.clone(),
+            communicating: device.communicating,
+            comm_level_sub_ghz: 0,
+            comm_level_2_4_ghz: 0,
+            last_report: device.last_rpt_date,
+        })
+        .collect();
+
+    Ok(Inventory {
+        battery_capacity: batteries.iter().map(|b| b.capacity_wh).sum(),
+        num_batteries: batteries.len(),
+        batteries,
+        ..Inventory::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy_api::{Dialect, serve_fixtures, test_client};
+
+    fn auth() -> EnvoyAuth {
+        EnvoyAuth::Digest {
+            username: "envoy".to_owned(),
+            password: "001234".to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn metered_state() {
+        let url = serve_fixtures(&[(
+            "/production.json",
+            include_str!("../../fixtures/envoy/legacy/production_metered.json"),
+        )])
+        .await;
+        let fetch = Dialect::Legacy
+            .fetch_state(&url, &auth(), &test_client(), &SystemState::default())
+            .await;
+        // the production CT has the daily totals, so nothing else is fetched
+        assert_eq!(fetch.endpoints.len(), 1);
+        assert!(fetch.endpoints[0].result.is_ok());
+        assert!(fetch.counters_fetched);
+
+        let state = fetch.state;
+        assert_eq!(state.last_update, DateTime::from_timestamp(1692806593, 0));
+        assert_eq!(state.pv_mw, 4213271);
+        assert_eq!(state.production_mwh_today, 18234874);
+        assert_eq!(state.production_mwh_seven_days, Some(171038874));
+        assert_eq!(state.load_mw, Some(1523480));
+        assert_eq!(state.consumption_mwh_today, Some(9876315));
+        assert_eq!(state.grid_mw, Some(-2689791));
+        // no AC Batteries are installed
+        assert_eq!(state.storage_mw, None);
+        assert_eq!(state.battery_soc, None);
+        assert_eq!(state.meters.len(), 3);
+        assert_eq!(state.meters[0].measurement_type, MeterType::Production);
+        assert_eq!(state.meters[0].phases.len(), 2);
+        assert_eq!(state.meters[0].phases[1].phase, "L2");
+        assert_eq!(state.meters[0].phases[1].frequency_hz, None);
+    }
+
+    #[tokio::test]
+    async fn unmetered_state() {
+        let url = serve_fixtures(&[
+            (
+                "/production.json",
+                include_str!("../../fixtures/envoy/legacy/production_unmetered.json"),
+            ),
+            (
+                "/api/v1/production",
+                include_str!("../../fixtures/envoy/legacy/api_v1_production.json"),
+            ),
+        ])
+        .await;
+        let fetch = Dialect::Legacy
+            .fetch_state(&url, &auth(), &test_client(), &SystemState::default())
+            .await;
+        assert_eq!(fetch.endpoints.len(), 2);
+        assert!(fetch.endpoints.iter().all(|outcome| outcome.result.is_ok()));
+        assert!(fetch.counters_fetched);
+
+        let state = fetch.state;
+        assert_eq!(state.last_update, DateTime::from_timestamp(1692806592, 0));
+        assert_eq!(state.pv_mw, 1850000);
+        assert_eq!(state.production_mwh_today, 6543000);
+        assert_eq!(state.production_mwh_lifetime, Some(8754321000));
+        assert_eq!(state.grid_mw, None);
+        assert_eq!(state.load_mw, None);
+        assert_eq!(state.consumption_mwh_today, None);
+        assert_eq!(state.storage_mw, Some(-260000));
+        assert_eq!(state.battery_soc, Some(66));
+        assert!(state.meters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn inventory() {
+        let url = serve_fixtures(&[(
+            "/inventory.json",
+            include_str!("../../fixtures/envoy/legacy/inventory.json"),
+        )])
+        .await;
+        let inventory = Dialect::Legacy
+            .fetch_inventory(&url, &auth(), &test_client())
+            .await
+            .unwrap();
+
+        assert_eq!(inventory.num_batteries, 1);
+        assert_eq!(inventory.battery_capacity, ACB_CAPACITY_WH);
+        let battery = &inventory.batteries[0];
+        assert_eq!(battery.serial, "121943012345");
+        assert_eq!(battery.percent_full, 66);
+        assert!(battery.communicating);
+        assert_eq!(
+            battery.last_report,
+            DateTime::from_timestamp(1692806410, 0).unwrap()
+        );
+        assert_eq!(inventory.system_controller, None);
+    }
+}
@@ -0,0 +1,471 @@
+//! Exporting history from the database as CSV or newline-delimited JSON, one
+//! row per timestamp with a column per series
+
+use chrono::{DateTime, Local, TimeDelta, Utc};
+use itertools::Itertools;
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+
+use crate::args::ExportArgs;
+use crate::migrations;
+use crate::state::HistoryKind;
+use crate::time_series::{Rollup, Statistics, Step};
+
+#[derive(clap::ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv; charset=utf-8",
+            Self::Ndjson => "application/x-ndjson",
+        }
+    }
+}
+
+/// One line of NDJSON, with its keys in the same order as the CSV columns
+struct NdjsonRow<'a> {
+    timestamp: &'a str,
+    series: &'a [HistoryKind],
+    values: &'a BTreeMap<HistoryKind, i64>,
+}
+
+impl Serialize for NdjsonRow<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.series.len() + 1))?;
+        map.serialize_entry("timestamp", self.timestamp)?;
+        for kind in self.series {
+            map.serialize_entry(kind.series_name(), &self.values.get(kind))?;
+        }
+        map.end()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExportQuery {
+    pub format: ExportFormat,
+    pub series: Vec<HistoryKind>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    /// Rows are averaged over each step; `Step::Raw` exports every reading,
+    /// or every rollup bucket from before the raw readings expired
+    pub step: Step,
+    /// Write timestamps in the local timezone rather than UTC, and start
+    /// days, weeks, and months at local midnight
+    pub local: bool,
+    /// How long a reading counts for when averaging over a step, the same
+    /// as in history queries
+    pub max_gap: TimeDelta,
+}
+
+impl ExportQuery {
+    pub fn from_args(args: &ExportArgs, max_gap: TimeDelta) -> Self {
+        Self {
+            format: args.format,
+            series: if args.series.is_empty() {
+                HistoryKind::ALL.to_vec()
+            } else {
+                args.series.clone()
+            },
+            from: args.from,
+            to: args.to,
+            step: args.step,
+            local: args.local,
+            max_gap,
+        }
+    }
+
+    fn header(&self) -> Option<String> {
+        match self.format {
+            ExportFormat::Csv => Some(format!(
+                "timestamp,{}\n",
+                self.series.iter().map(|kind| kind.series_name()).join(",")
+            )),
+            ExportFormat::Ndjson => None,
+        }
+    }
+
+    fn render(&self, timestamp: DateTime<Utc>, values: &BTreeMap<HistoryKind, i64>) -> String {
+        let timestamp = if self.local {
+            timestamp.with_timezone(&Local).to_rfc3339()
+        } else {
+            timestamp.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        };
+        match self.format {
+            ExportFormat::Csv => {
+                let mut line = timestamp;
+                for kind in &self.series {
+                    line.push(',');
+                    if let Some(value) = values.get(kind) {
+                        line.push_str(&value.to_string());
+                    }
+                }
+                line.push('\n');
+                line
+            }
+            ExportFormat::Ndjson => {
+                let row = NdjsonRow {
+                    timestamp: &timestamp,
+                    series: &self.series,
+                    values,
+                };
+                let mut line = serde_json::to_string(&row).unwrap_or_default();
+                line.push('\n');
+                line
+            }
+        }
+    }
+
+    /// Which bucket of `step` a row at `timestamp` belongs to
+    fn bucket(&self, timestamp: &DateTime<Utc>) -> DateTime<Utc> {
+        if self.local {
+            self.step.truncate_local(timestamp)
+        } else {
+            self.step.truncate(timestamp)
+        }
+    }
+
+    /// Where the bucket starting at `start` ends
+    fn bucket_end(&self, start: &DateTime<Utc>) -> DateTime<Utc> {
+        if self.local {
+            self.step.end_local(start)
+        } else {
+            self.step.end(start)
+        }
+    }
+
+    /// Read the matching history from `db`, handing it to `write` a chunk of
+    /// lines at a time so that nothing has to be held in memory
+    pub fn run(
+        &self,
+        db: &rusqlite::Connection,
+        mut write: impl FnMut(String) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        const CHUNK_SIZE: usize = 8192;
+
+        let mut chunk = self.header().unwrap_or_default();
+        // raw readings expire before the rollups do, so older rows are the
+        // finest rollup that goes back that far, up to where the finer data
+        // starts
+        let mut selects = vec![
+            "SELECT timestamp, kind, value, 1, 0, 0, 1 FROM history WHERE timestamp BETWEEN ?1 AND ?2"
+                .to_owned(),
+        ];
+        let mut finer_start = earliest(db, "history")?;
+        for rollup in Rollup::ALL {
+            let before = finer_start.map_or(i64::MAX, |start| rollup.truncate(&start).timestamp());
+            selects.push(format!(
+                "SELECT timestamp, kind, sum, count, COALESCE(integral, 0), COALESCE(covered_secs, 0), 0 FROM {} WHERE timestamp BETWEEN ?1 AND ?2 AND timestamp < {before}",
+                rollup.table()
+            ));
+            finer_start = finer_start
+                .into_iter()
+                .chain(earliest(db, rollup.table())?)
+                .min();
+        }
+        let mut stmt = db.prepare(&format!(
+            "{} ORDER BY timestamp ASC, kind ASC",
+            selects.join(" UNION ALL ")
+        ))?;
+        let from = self.from.map_or(i64::MIN, |from| from.timestamp());
+        let to = self.to.map_or(i64::MAX, |to| to.timestamp());
+        // the last reading of each series hasn't held for any time yet, the
+        // same as in history queries
+        let last_readings: BTreeMap<HistoryKind, DateTime<Utc>> = db
+            .prepare(
+                "SELECT kind, MAX(timestamp) FROM history WHERE timestamp BETWEEN ?1 AND ?2 GROUP BY kind",
+            )?
+            .query_map((from, to), |row| {
+                Ok((row.get::<_, u8>(0)?, row.get::<_, i64>(1)?))
+            })?
+            .filter_map(|row| {
+                let (kind, timestamp) = row.ok()?;
+                Some((
+                    HistoryKind::try_from(kind).ok()?,
+                    DateTime::from_timestamp(timestamp, 0)?,
+                ))
+            })
+            .collect();
+        let mut rows = stmt.query((from, to))?;
+
+        // buckets that readings could still go into, and the latest reading
+        // of each series along with how far it's been counted as holding for
+        let mut buckets: BTreeMap<DateTime<Utc>, BTreeMap<HistoryKind, Totals>> = BTreeMap::new();
+        let mut holding: BTreeMap<HistoryKind, HeldReading> = BTreeMap::new();
+        let hold = |buckets: &mut BTreeMap<DateTime<Utc>, BTreeMap<HistoryKind, Totals>>,
+                    kind: HistoryKind,
+                    mut from: DateTime<Utc>,
+                    until: DateTime<Utc>,
+                    value: i64| {
+            let mut bucket = self.bucket(&from);
+            while from < until {
+                let end = self.bucket_end(&bucket);
+                let secs = (end.min(until) - from).num_seconds();
+                let totals = buckets.entry(bucket).or_default().entry(kind).or_default();
+                totals.integral += value * secs;
+                totals.covered_secs += secs;
+                from = end;
+                bucket = end;
+            }
+        };
+        // write out every bucket that ends by `before`, or all of them
+        let mut flush = |buckets: &mut BTreeMap<DateTime<Utc>, BTreeMap<HistoryKind, Totals>>,
+                         before: Option<DateTime<Utc>>,
+                         chunk: &mut String|
+         -> anyhow::Result<()> {
+            while let Some(entry) = buckets.first_entry() {
+                if before.is_some_and(|before| {
+                    *entry.key() >= before || self.bucket_end(entry.key()) > before
+                }) {
+                    break;
+                }
+                let (bucket, totals) = entry.remove_entry();
+                let values = totals
+                    .into_iter()
+                    .map(|(kind, totals)| (kind, totals.average()))
+                    .collect();
+                chunk.push_str(&self.render(bucket, &values));
+                if chunk.len() >= CHUNK_SIZE {
+                    write(std::mem::take(chunk))?;
+                }
+            }
+            Ok(())
+        };
+        while let Some(row) = rows.next()? {
+            let Ok(kind) = HistoryKind::try_from(row.get::<_, u8>(1)?) else {
+                continue;
+            };
+            if !self.series.contains(&kind) {
+                continue;
+            }
+            let timestamp = DateTime::<Utc>::from_timestamp(row.get(0)?, 0)
+                .ok_or_else(|| anyhow::anyhow!("invalid timestamp"))?;
+            let row_totals = Totals {
+                sum: row.get(2)?,
+                count: row.get::<_, i64>(3)? as usize,
+                integral: row.get(4)?,
+                covered_secs: row.get(5)?,
+            };
+            let raw: bool = row.get(6)?;
+
+            if self.step == Step::Raw {
+                flush(&mut buckets, Some(timestamp), &mut chunk)?;
+                buckets
+                    .entry(timestamp)
+                    .or_default()
+                    .insert(kind, row_totals);
+                continue;
+            }
+            // every series still holding has a later reading, and it's no
+            // earlier than this one
+            for (kind, held) in holding.iter_mut() {
+                let until = (held.timestamp + self.max_gap).min(timestamp);
+                if until > held.counted_until {
+                    hold(&mut buckets, *kind, held.counted_until, until, held.value);
+                    held.counted_until = until;
+                }
+            }
+            flush(&mut buckets, Some(timestamp), &mut chunk)?;
+            buckets
+                .entry(self.bucket(&timestamp))
+                .or_default()
+                .entry(kind)
+                .or_default()
+                .add(&row_totals);
+            if raw && last_readings.get(&kind) != Some(&timestamp) {
+                holding.insert(
+                    kind,
+                    HeldReading {
+                        timestamp,
+                        value: row_totals.sum,
+                        counted_until: timestamp,
+                    },
+                );
+            } else {
+                holding.remove(&kind);
+            }
+        }
+        flush(&mut buckets, None, &mut chunk)?;
+        if !chunk.is_empty() {
+            write(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// A raw reading that holds until the next one of its series
+struct HeldReading {
+    timestamp: DateTime<Utc>,
+    value: i64,
+    counted_until: DateTime<Utc>,
+}
+
+/// What's gone into one series' share of an export bucket so far
+#[derive(Debug, Default, Clone, Copy)]
+struct Totals {
+    sum: i64,
+    count: usize,
+    integral: i64,
+    covered_secs: i64,
+}
+
+impl Totals {
+    fn add(&mut self, other: &Totals) {
+        self.sum += other.sum;
+        self.count += other.count;
+        self.integral += other.integral;
+        self.covered_secs += other.covered_secs;
+    }
+
+    /// Weighted by how long each reading held for, the same as in history
+    /// queries
+    fn average(&self) -> i64 {
+        Statistics::from_parts(
+            self.sum,
+            self.count,
+            None,
+            None,
+            self.integral,
+            None,
+            self.covered_secs,
+            0,
+        )
+        .average
+    }
+}
+
+/// When the oldest row in `table` is from
+fn earliest(db: &rusqlite::Connection, table: &str) -> anyhow::Result<Option<DateTime<Utc>>> {
+    let earliest: Option<i64> =
+        db.query_row(&format!("SELECT MIN(timestamp) FROM {table}"), [], |row| {
+            row.get(0)
+        })?;
+    Ok(earliest.and_then(|timestamp| DateTime::from_timestamp(timestamp, 0)))
+}
+
+/// Open the database read-only, so that exports can run alongside the server
+pub fn open_database(path: &Path) -> anyhow::Result<rusqlite::Connection> {
+    let db = rusqlite::Connection::open_with_flags(
+        path,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    migrations::ensure_supported(&db)?;
+    Ok(db)
+}
+
+/// The `export` subcommand
+pub fn run_cli(state_path: &Path, max_gap: TimeDelta, args: &ExportArgs) -> anyhow::Result<()> {
+    let db = open_database(state_path)?;
+    let query = ExportQuery::from_args(args, max_gap);
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path)?)),
+        None => Box::new(std::io::BufWriter::new(std::io::stdout().lock())),
+    };
+    query.run(&db, |chunk| Ok(out.write_all(chunk.as_bytes())?))?;
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time_series::{Aggregation, History, bucketize};
+
+    #[test]
+    fn older_rows_come_from_rollups() {
+        let mut db = rusqlite::Connection::open_in_memory().unwrap();
+        migrations::migrate(&mut db, Path::new(":memory:")).unwrap();
+        let pv = HistoryKind::Pv as u8;
+        // two hours, the first of which has only been kept as a rollup
+        db.execute_batch(&format!(
+            "INSERT INTO history_hourly VALUES ({pv}, 1748736000, 3000, 2, 1000, 2000, 5400000, 3600, 5400000);
+            INSERT INTO history_5m VALUES ({pv}, 1748739600, 3000, 1, 3000, 3000, 900000, 300, 900000);
+            INSERT INTO history VALUES ({pv}, 1748739600, 3000);
+            INSERT INTO history VALUES ({pv}, 1748739660, 3100);"
+        ))
+        .unwrap();
+
+        let query = ExportQuery {
+            format: ExportFormat::Csv,
+            series: vec![HistoryKind::Pv],
+            from: None,
+            to: None,
+            step: Step::Raw,
+            local: false,
+            max_gap: TimeDelta::minutes(15),
+        };
+        let mut output = String::new();
+        query
+            .run(&db, |chunk| {
+                output.push_str(&chunk);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            output,
+            "timestamp,pv_mw\n2025-06-01T00:00:00Z,1500\n2025-06-01T01:00:00Z,3000\n2025-06-01T01:01:00Z,3100\n"
+        );
+    }
+
+    #[test]
+    fn steps_average_like_history_queries() {
+        let mut db = rusqlite::Connection::open_in_memory().unwrap();
+        migrations::migrate(&mut db, Path::new(":memory:")).unwrap();
+        let pv = HistoryKind::Pv as u8;
+        let start = DateTime::from_timestamp(1748736000, 0).unwrap();
+        let points: BTreeMap<DateTime<Utc>, i64> = [(0, 1000), (50, 4000), (70, 2000), (80, 2000)]
+            .into_iter()
+            .map(|(minutes, value)| (start + TimeDelta::minutes(minutes), value))
+            .collect();
+        for (timestamp, value) in &points {
+            db.execute(
+                "INSERT INTO history VALUES (?1, ?2, ?3)",
+                (pv, timestamp.timestamp(), value),
+            )
+            .unwrap();
+        }
+
+        let max_gap = TimeDelta::hours(1);
+        let query = ExportQuery {
+            format: ExportFormat::Csv,
+            series: vec![HistoryKind::Pv],
+            from: None,
+            to: None,
+            step: Step::Hour,
+            local: false,
+            max_gap,
+        };
+        let mut output = String::new();
+        query
+            .run(&db, |chunk| {
+                output.push_str(&chunk);
+                Ok(())
+            })
+            .unwrap();
+        let expected = bucketize(&History::Raw(points), Step::Hour, Aggregation::Avg, max_gap)
+            .into_iter()
+            .map(|point| {
+                format!(
+                    "{},{}\n",
+                    point
+                        .timestamp
+                        .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                    point.value
+                )
+            })
+            .join("");
+        assert_eq!(output, format!("timestamp,pv_mw\n{expected}"));
+        // the 50 minutes at 1000 count for more than the 10 at 4000
+        assert_eq!(
+            output,
+            "timestamp,pv_mw\n2025-06-01T00:00:00Z,1500\n2025-06-01T01:00:00Z,3000\n"
+        );
+    }
+}
@@ -1,4 +1,7 @@
-use axum::{Router, routing::get};
+use axum::{
+    Router,
+    routing::{get, post},
+};
 use clap::Parser;
 use mimalloc::MiMalloc;
 use std::sync::Arc;
@@ -8,13 +11,19 @@ use tracing_subscriber::prelude::*;
 mod api;
 mod args;
 mod envoy_api;
+mod export;
+mod migrations;
+mod refresh;
+mod solar;
 mod state;
 mod tasks;
 mod time_series;
+mod token;
 
-use crate::args::Args;
+use crate::args::{Args, Command};
 use crate::state::AppState;
 use crate::tasks::BackgroundTask;
+use crate::token::TokenManager;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
@@ -48,12 +57,19 @@ async fn shutdown_signal(shutdown_tx: broadcast::Sender<()>) {
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
+    if let Some(Command::Export(export_args)) = &args.command {
+        return export::run_cli(&args.state_path, args.history_max_gap(), export_args);
+    }
+    args.validate_retention()?;
+
     tracing_subscriber::registry()
         .with(tracing_subscriber::fmt::layer())
         .with(tracing_subscriber::EnvFilter::from_default_env())
         .init();
 
-    let state = Arc::new(AppState::new(&args.state_path)?);
+    let tokens = TokenManager::initialize(&args).await?;
+
+    let state = Arc::new(AppState::new(&args, tokens)?);
 
     let (shutdown_tx, mut shutdown_rx) = broadcast::channel(1);
 
@@ -65,11 +81,33 @@ async fn main() -> anyhow::Result<()> {
         args.clone(),
         shutdown_rx.resubscribe(),
     ));
+    if args.live_stream {
+        tokio::spawn(tasks::StreamLiveData::start(
+            Arc::clone(&state),
+            args.clone(),
+            shutdown_rx.resubscribe(),
+        ));
+    }
     tokio::spawn(tasks::FetchInventory::start(
         Arc::clone(&state),
         args.clone(),
         shutdown_rx.resubscribe(),
     ));
+    tokio::spawn(tasks::FetchInverters::start(
+        Arc::clone(&state),
+        args.clone(),
+        shutdown_rx.resubscribe(),
+    ));
+    tokio::spawn(tasks::FetchBatteries::start(
+        Arc::clone(&state),
+        args.clone(),
+        shutdown_rx.resubscribe(),
+    ));
+    tokio::spawn(tasks::RefreshToken::start(
+        Arc::clone(&state),
+        args.clone(),
+        shutdown_rx.resubscribe(),
+    ));
     tokio::spawn(tasks::MaintainState::start(
         Arc::clone(&state),
         args.clone(),
@@ -82,6 +120,13 @@ async fn main() -> anyhow::Result<()> {
         .route("/metrics.json", get(api::metrics_json))
         .route("/metrics", get(api::metrics_prom))
         .route("/health/ok", get(api::healthcheck))
+        .route("/status/tasks", get(api::task_status))
+        .route("/refresh", post(api::refresh))
+        .route("/api/history", get(api::history))
+        .route("/export.csv", get(api::export_csv))
+        .route("/export.ndjson", get(api::export_ndjson))
+        .route("/events", get(api::events_sse))
+        .route("/events/ws", get(api::events_ws))
         .route("/", get(api::root))
         .with_state(state);
     let listener = tokio::net::TcpListener::bind(("::", args.port))
@@ -0,0 +1,271 @@
+//! Versioned changes to the SQLite schema, tracked with `PRAGMA user_version`
+
+use anyhow::Context;
+use chrono::Utc;
+use std::path::Path;
+
+/// Each migration takes the schema from the version before it to its own
+/// (1-based) position in this list. Databases from before versioning already
+/// have the tables from the first one, so it has to be safe to re-run.
+const MIGRATIONS: &[&str] = &[
+    // 1: readings, inverters and batteries
+    r#"
+    CREATE TABLE IF NOT EXISTS history(
+        kind INTEGER NOT NULL,
+        timestamp BIGINT NOT NULL,
+        value BIGINT NOT NULL,
+        PRIMARY KEY (kind, timestamp)
+    );
+    CREATE INDEX IF NOT EXISTS idx_history_on_timestamp ON history(timestamp);
+    CREATE TABLE IF NOT EXISTS inverter_history(
+        serial TEXT NOT NULL,
+        timestamp BIGINT NOT NULL,
+        watts BIGINT NOT NULL,
+        PRIMARY KEY (serial, timestamp)
+    );
+    CREATE INDEX IF NOT EXISTS idx_inverter_history_on_timestamp ON inverter_history(timestamp);
+    CREATE TABLE IF NOT EXISTS battery_history(
+        serial TEXT NOT NULL,
+        timestamp BIGINT NOT NULL,
+        soc INTEGER NOT NULL,
+        PRIMARY KEY (serial, timestamp)
+    );
+    CREATE INDEX IF NOT EXISTS idx_battery_history_on_timestamp ON battery_history(timestamp);
+    "#,
+    // 2: rollups of history
+    r#"
+    CREATE TABLE IF NOT EXISTS history_5m(
+        kind INTEGER NOT NULL,
+        timestamp BIGINT NOT NULL,
+        sum BIGINT NOT NULL,
+        count BIGINT NOT NULL,
+        min BIGINT,
+        max BIGINT,
+        PRIMARY KEY (kind, timestamp)
+    );
+    CREATE TABLE IF NOT EXISTS history_hourly(
+        kind INTEGER NOT NULL,
+        timestamp BIGINT NOT NULL,
+        sum BIGINT NOT NULL,
+        count BIGINT NOT NULL,
+        min BIGINT,
+        max BIGINT,
+        PRIMARY KEY (kind, timestamp)
+    );
+    CREATE TABLE IF NOT EXISTS history_daily(
+        kind INTEGER NOT NULL,
+        timestamp BIGINT NOT NULL,
+        sum BIGINT NOT NULL,
+        count BIGINT NOT NULL,
+        min BIGINT,
+        max BIGINT,
+        PRIMARY KEY (kind, timestamp)
+    );
+    CREATE TABLE IF NOT EXISTS history_monthly(
+        kind INTEGER NOT NULL,
+        timestamp BIGINT NOT NULL,
+        sum BIGINT NOT NULL,
+        count BIGINT NOT NULL,
+        min BIGINT,
+        max BIGINT,
+        PRIMARY KEY (kind, timestamp)
+    );
+    "#,
+    // 3: end-of-day energy totals
+    r#"
+    CREATE TABLE IF NOT EXISTS daily_totals(
+        kind INTEGER NOT NULL,
+        date TEXT NOT NULL,
+        value BIGINT NOT NULL,
+        PRIMARY KEY (kind, date)
+    );
+    "#,
+    // 4: time-weighted rollups
+    r#"
+    ALTER TABLE history_5m ADD COLUMN integral BIGINT;
+    ALTER TABLE history_5m ADD COLUMN covered_secs BIGINT;
+    ALTER TABLE history_hourly ADD COLUMN integral BIGINT;
+    ALTER TABLE history_hourly ADD COLUMN covered_secs BIGINT;
+    ALTER TABLE history_daily ADD COLUMN integral BIGINT;
+    ALTER TABLE history_daily ADD COLUMN covered_secs BIGINT;
+    ALTER TABLE history_monthly ADD COLUMN integral BIGINT;
+    ALTER TABLE history_monthly ADD COLUMN covered_secs BIGINT;
+    "#,
+    // 5: energy in and out of rollups
+    r#"
+    ALTER TABLE history_5m ADD COLUMN positive_integral BIGINT;
+    ALTER TABLE history_hourly ADD COLUMN positive_integral BIGINT;
+    ALTER TABLE history_daily ADD COLUMN positive_integral BIGINT;
+    ALTER TABLE history_monthly ADD COLUMN positive_integral BIGINT;
+    "#,
+];
+
+/// The schema version this build writes
+pub const SCHEMA_VERSION: u32 = MIGRATIONS.len() as u32;
+
+fn schema_version(db: &rusqlite::Connection) -> anyhow::Result<u32> {
+    Ok(db.pragma_query_value(None, "user_version", |row| row.get(0))?)
+}
+
+/// Refuse to touch a database written by a newer version of this program
+pub fn ensure_supported(db: &rusqlite::Connection) -> anyhow::Result<u32> {
+    let version = schema_version(db)?;
+    if version > SCHEMA_VERSION {
+        anyhow::bail!(
+            "database has schema version {version}, but this version of envoyproxy only understands up to {SCHEMA_VERSION}; upgrade envoyproxy or use a different STATE_PATH"
+        );
+    }
+    Ok(version)
+}
+
+/// Bring the database at `path` up to date, taking a backup first if it has
+/// anything in it
+pub fn migrate(db: &mut rusqlite::Connection, path: &Path) -> anyhow::Result<()> {
+    let version = ensure_supported(db)?;
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    let has_tables: bool = db.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table')",
+        [],
+        |row| row.get(0),
+    )?;
+    if has_tables {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(format!(
+            ".v{version}-{}.bak",
+            Utc::now().format("%Y%m%d%H%M%S")
+        ));
+        tracing::info!(?backup, "backing up database before migrating it");
+        db.execute("VACUUM INTO ?1", [backup.to_string_lossy()])
+            .context("failed to back up database")?;
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let target = i as u32 + 1;
+        tracing::info!(from = target - 1, to = target, "migrating database");
+        let tx = db.transaction()?;
+        tx.execute_batch(migration)
+            .with_context(|| format!("failed to migrate database to version {target}"))?;
+        tx.pragma_update(None, "user_version", target)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Dumps of databases written by earlier versions, each with a couple of
+    /// readings (and whatever else that version kept) in it
+    const OLDER_DATABASES: [(&str, u32, &str); 5] = [
+        (
+            "v0_readings_only",
+            0,
+            include_str!("../fixtures/db/v0_readings_only.sql"),
+        ),
+        ("v0", 0, include_str!("../fixtures/db/v0.sql")),
+        ("v2", 2, include_str!("../fixtures/db/v2.sql")),
+        ("v3", 3, include_str!("../fixtures/db/v3.sql")),
+        ("v4", 4, include_str!("../fixtures/db/v4.sql")),
+    ];
+
+    fn columns(db: &rusqlite::Connection, table: &str) -> Vec<String> {
+        let mut stmt = db
+            .prepare("SELECT name FROM pragma_table_info(?1)")
+            .unwrap();
+        stmt.query_map([table], |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap()
+    }
+
+    fn count(db: &rusqlite::Connection, table: &str) -> i64 {
+        db.query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+            row.get(0)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn migrates_older_databases() {
+        let dir = std::env::temp_dir();
+        for (name, version, dump) in OLDER_DATABASES {
+            let mut db = rusqlite::Connection::open_in_memory().unwrap();
+            db.execute_batch(dump).unwrap();
+            assert_eq!(schema_version(&db).unwrap(), version, "{name}");
+            let had_rollups = !columns(&db, "history_5m").is_empty();
+            let path = dir.join(format!(
+                "envoyproxy-migrations-{}-{name}.db",
+                std::process::id()
+            ));
+            migrate(&mut db, &path).unwrap();
+
+            assert_eq!(schema_version(&db).unwrap(), SCHEMA_VERSION, "{name}");
+            for table in [
+                "history_5m",
+                "history_hourly",
+                "history_daily",
+                "history_monthly",
+            ] {
+                let columns = columns(&db, table);
+                for column in [
+                    "sum",
+                    "count",
+                    "integral",
+                    "covered_secs",
+                    "positive_integral",
+                ] {
+                    assert!(
+                        columns.iter().any(|c| c == column),
+                        "{name}: {table} has no {column}"
+                    );
+                }
+                assert_eq!(count(&db, table), i64::from(had_rollups), "{name}: {table}");
+            }
+            assert_eq!(columns(&db, "daily_totals"), ["kind", "date", "value"]);
+            assert_eq!(count(&db, "history"), 2, "{name}");
+            if had_rollups {
+                let sum: i64 = db
+                    .query_row("SELECT sum FROM history_hourly", [], |row| row.get(0))
+                    .unwrap();
+                assert_eq!(sum, 3000, "{name}");
+                assert_eq!(count(&db, "inverter_history"), 1, "{name}");
+                assert_eq!(count(&db, "battery_history"), 1, "{name}");
+            }
+
+            let backups: Vec<_> = std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|backup| {
+                    backup
+                        .file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .starts_with(&format!(
+                            "{}.v{version}-",
+                            path.file_name().unwrap().to_string_lossy()
+                        ))
+                })
+                .collect();
+            assert_eq!(backups.len(), 1, "no backup of {name}");
+            let backup = rusqlite::Connection::open(&backups[0]).unwrap();
+            assert_eq!(schema_version(&backup).unwrap(), version);
+            assert_eq!(count(&backup, "history"), 2);
+            drop(backup);
+            std::fs::remove_file(&backups[0]).unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_newer_versions() {
+        let mut db = rusqlite::Connection::open_in_memory().unwrap();
+        migrate(&mut db, Path::new(":memory:")).unwrap();
+        db.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        assert!(ensure_supported(&db).is_err());
+        assert!(migrate(&mut db, Path::new("unused.db")).is_err());
+    }
+}
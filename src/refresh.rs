@@ -0,0 +1,106 @@
+//! On-demand refreshes of the system state, which are run by the regular
+//! `FetchState` task so that they share its timer and never overlap with it
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, watch};
+
+/// How long a caller will wait for a refresh before giving up on it
+const REFRESH_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub enum RefreshOutcome {
+    Refreshed,
+    Failed(String),
+    /// We fetched recently enough that the Envoy shouldn't be asked again yet
+    TooSoon(Duration),
+}
+
+#[derive(Debug, Default)]
+struct Progress {
+    running: bool,
+    last_finished: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Completed {
+    generation: u64,
+    error: Option<String>,
+}
+
+pub struct RefreshCoordinator {
+    min_interval: Duration,
+    progress: Mutex<Progress>,
+    requested: AtomicBool,
+    notify: Notify,
+    completed: watch::Sender<Completed>,
+}
+
+impl RefreshCoordinator {
+    pub fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            progress: Mutex::new(Progress::default()),
+            requested: AtomicBool::new(false),
+            notify: Notify::new(),
+            completed: watch::Sender::new(Completed::default()),
+        }
+    }
+
+    /// Ask for a refresh and wait for it to finish; if one is already running,
+    /// wait for that one instead of starting another
+    pub async fn request(&self) -> RefreshOutcome {
+        let mut completed_rx = self.completed.subscribe();
+        let target = completed_rx.borrow_and_update().generation + 1;
+        {
+            let progress = self.progress.lock().unwrap();
+            if !progress.running {
+                if let Some(last_finished) = progress.last_finished
+                    && last_finished.elapsed() < self.min_interval
+                {
+                    return RefreshOutcome::TooSoon(self.min_interval - last_finished.elapsed());
+                }
+                self.requested.store(true, Ordering::SeqCst);
+                self.notify.notify_one();
+            }
+        }
+        let wait = completed_rx.wait_for(|completed| completed.generation >= target);
+        match tokio::time::timeout(REFRESH_TIMEOUT, wait).await {
+            Ok(Ok(completed)) => match &completed.error {
+                None => RefreshOutcome::Refreshed,
+                Some(error) => RefreshOutcome::Failed(error.clone()),
+            },
+            Ok(Err(_)) => RefreshOutcome::Failed("state fetching has stopped".to_owned()),
+            Err(_) => RefreshOutcome::Failed("timed out waiting for a refresh".to_owned()),
+        }
+    }
+
+    /// Resolves once someone has asked for a refresh
+    pub async fn requested(&self) {
+        loop {
+            self.notify.notified().await;
+            // a request that arrived while a fetch was already starting has
+            // been taken care of by that fetch
+            if self.requested.swap(false, Ordering::SeqCst) {
+                return;
+            }
+        }
+    }
+
+    pub fn begin(&self) {
+        self.progress.lock().unwrap().running = true;
+        self.requested.store(false, Ordering::SeqCst);
+    }
+
+    pub fn finish(&self, result: &anyhow::Result<()>) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.running = false;
+        progress.last_finished = Some(Instant::now());
+        drop(progress);
+        self.completed.send_modify(|completed| {
+            completed.generation += 1;
+            completed.error = result.as_ref().err().map(|error| format!("{error:#}"));
+        });
+    }
+}
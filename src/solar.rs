@@ -0,0 +1,83 @@
+//! Just enough astronomy to tell whether the sun is up
+
+use chrono::{DateTime, Utc};
+
+/// The sun's center is this far below the horizon at sunrise and sunset, once
+/// refraction and the size of its disc are accounted for
+const SUNRISE_ELEVATION_DEGREES: f64 = -0.833;
+
+/// Approximate elevation of the sun above the horizon, in degrees, using the
+/// low-precision algorithm from the Astronomical Almanac (good to about a
+/// hundredth of a degree, which is plenty for scheduling)
+pub fn elevation_degrees(at: DateTime<Utc>, latitude: f64, longitude: f64) -> f64 {
+    // days since the J2000 epoch
+    let n = at.timestamp() as f64 / 86400.0 + 2440587.5 - 2451545.0;
+    let mean_longitude = (280.460 + 0.9856474 * n).rem_euclid(360.0);
+    let mean_anomaly = (357.528 + 0.9856003 * n).rem_euclid(360.0).to_radians();
+    let ecliptic_longitude =
+        (mean_longitude + 1.915 * mean_anomaly.sin() + 0.020 * (2.0 * mean_anomaly).sin())
+            .to_radians();
+    let obliquity = (23.439 - 0.0000004 * n).to_radians();
+
+    let declination = (obliquity.sin() * ecliptic_longitude.sin()).asin();
+    let right_ascension = (obliquity.cos() * ecliptic_longitude.sin())
+        .atan2(ecliptic_longitude.cos())
+        .to_degrees();
+    let sidereal_degrees = (18.697374558 + 24.06570982441908 * n).rem_euclid(24.0) * 15.0;
+    let hour_angle = (sidereal_degrees + longitude - right_ascension).to_radians();
+
+    let latitude = latitude.to_radians();
+    (latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour_angle.cos())
+        .asin()
+        .to_degrees()
+}
+
+/// Whether `at` falls between sunrise and sunset at this location
+pub fn is_daylight(at: DateTime<Utc>, latitude: f64, longitude: f64) -> bool {
+    elevation_degrees(at, latitude, longitude) > SUNRISE_ELEVATION_DEGREES
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(rfc3339: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(rfc3339).unwrap().to_utc()
+    }
+
+    const TROMSO: (f64, f64) = (69.65, 18.96);
+
+    #[test]
+    fn equinox_noon_at_the_equator() {
+        // the sun is overhead, give or take how far it's moved since the
+        // equinox that morning
+        let elevation = elevation_degrees(at("2024-03-20T12:07:00Z"), 0.0, 0.0);
+        assert!(elevation > 89.5, "{elevation}");
+        let elevation = elevation_degrees(at("2024-03-20T00:07:00Z"), 0.0, 0.0);
+        assert!(elevation < -89.5, "{elevation}");
+    }
+
+    #[test]
+    fn solstice_noon() {
+        // 90° - latitude + the sun's declination of 23.44°
+        let elevation = elevation_degrees(at("2024-06-20T20:12:00Z"), 37.7749, -122.4194);
+        assert!((elevation - 75.67).abs() < 0.1, "{elevation}");
+    }
+
+    #[test]
+    fn polar_night() {
+        // even at noon, the sun stays 90° - 69.65° - 23.44° below the horizon
+        let noon = at("2024-12-21T10:45:00Z");
+        let elevation = elevation_degrees(noon, TROMSO.0, TROMSO.1);
+        assert!((elevation + 3.09).abs() < 0.1, "{elevation}");
+        assert!(!is_daylight(noon, TROMSO.0, TROMSO.1));
+    }
+
+    #[test]
+    fn midnight_sun() {
+        let midnight = at("2024-06-20T22:45:00Z");
+        let elevation = elevation_degrees(midnight, TROMSO.0, TROMSO.1);
+        assert!((elevation - 3.09).abs() < 0.1, "{elevation}");
+        assert!(is_daylight(midnight, TROMSO.0, TROMSO.1));
+    }
+}
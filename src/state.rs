@@ -1,40 +1,349 @@
 use anyhow::Context;
-use chrono::{DateTime, TimeDelta, Utc};
+use chrono::{DateTime, Local, NaiveDate, NaiveTime, TimeDelta, Utc};
 use serde::Serialize;
-use std::path::Path;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, broadcast};
+use url::Url;
 
-use crate::envoy_api::GridState;
-use crate::time_series::{TimeSeriesRow, TimeSeriesSummary};
+use crate::args::Args;
+use crate::envoy_api::{
+    self, Dialect, EndpointOutcome, EnvoyAuth, GridState, MeterType, RelayState,
+};
+use crate::migrations;
+use crate::refresh::RefreshCoordinator;
+use crate::time_series::{
+    self, Aggregation, ClosedBucket, DailyTotalSummary, History, HistoryPoint, Retention, Rollup,
+    SeriesSummary, Statistics, Step, TimeSeriesRow,
+};
+use crate::token::{EnvoyToken, TokenManager};
+
+/// How many events a slow `/events` subscriber can fall behind by before it
+/// gets a fresh snapshot instead
+const EVENT_BUFFER: usize = 64;
+/// The most readings a single history query will load for each series
+pub const MAX_HISTORY_POINTS: usize = 100_000;
 
 #[derive(Serialize, Debug, Default, Clone)]
 pub struct SystemState {
     pub last_update: Option<DateTime<Utc>>,
-    pub battery_soc: u32,
     pub pv_mw: i64,
-    pub storage_mw: i64,
-    pub grid_mw: i64,
-    pub load_mw: i64,
+    // these are only known for systems with batteries or consumption CTs
+    pub battery_soc: Option<u32>,
+    pub storage_mw: Option<i64>,
+    pub grid_mw: Option<i64>,
+    pub load_mw: Option<i64>,
     pub production_mwh_today: i64,
-    pub consumption_mwh_today: i64,
+    pub consumption_mwh_today: Option<i64>,
+    /// When the energy counters below were last read, which can lag behind
+    /// `last_update` if the Envoy serves them separately
+    pub energy_last_update: Option<DateTime<Utc>>,
+    // unknown until the Envoy first reports its energy counters
+    pub production_mwh_seven_days: Option<i64>,
+    pub consumption_mwh_seven_days: Option<i64>,
+    pub production_mwh_lifetime: Option<i64>,
+    pub consumption_mwh_lifetime: Option<i64>,
+    pub production_mw_now: i64,
+    pub consumption_mw_now: Option<i64>,
+    pub meters: Vec<MeterReading>,
 }
 
-#[derive(Serialize, Debug, Default, Clone)]
+impl SystemState {
+    /// The fields which differ from `previous`, keyed as they're serialized
+    fn delta_from(&self, previous: &SystemState) -> serde_json::Map<String, serde_json::Value> {
+        let (Ok(serde_json::Value::Object(current)), Ok(serde_json::Value::Object(previous))) =
+            (serde_json::to_value(self), serde_json::to_value(previous))
+        else {
+            return serde_json::Map::new();
+        };
+        current
+            .into_iter()
+            .filter(|(key, value)| previous.get(key) != Some(value))
+            .collect()
+    }
+
+    /// The values we keep history for, skipping any this system can't measure
+    fn history_values(&self) -> Vec<(HistoryKind, i64)> {
+        [
+            (HistoryKind::Pv, Some(self.pv_mw)),
+            (HistoryKind::Grid, self.grid_mw),
+            (HistoryKind::Load, self.load_mw),
+            (HistoryKind::Storage, self.storage_mw),
+            (HistoryKind::Soc, self.battery_soc.map(i64::from)),
+        ]
+        .into_iter()
+        .filter_map(|(kind, value)| Some((kind, value?)))
+        .collect()
+    }
+
+    /// The counters we keep history for, which (unlike `history_values`)
+    /// come only from polling
+    fn daily_total_values(&self) -> Vec<(HistoryKind, i64)> {
+        [
+            (
+                HistoryKind::ProductionToday,
+                Some(self.production_mwh_today),
+            ),
+            (HistoryKind::ConsumptionToday, self.consumption_mwh_today),
+        ]
+        .into_iter()
+        .filter_map(|(kind, value)| Some((kind, value?)))
+        .collect()
+    }
+}
+
+/// Changes pushed to anyone watching `/events`
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum StateEvent {
+    /// Everything, for new subscribers and ones that have fallen behind
+    Snapshot {
+        state: Box<SystemState>,
+        inventory: Inventory,
+    },
+    /// Only the fields of `SystemState` which changed
+    State(serde_json::Map<String, serde_json::Value>),
+    Inventory(Inventory),
+    GridState(Option<GridState>),
+}
+
+impl StateEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Snapshot { .. } => "snapshot",
+            Self::State(_) => "state",
+            Self::Inventory(_) => "inventory",
+            Self::GridState(_) => "grid_state",
+        }
+    }
+}
+
+/// Instantaneous power flows, which the Envoy can update every second or so
+#[derive(Debug, Clone)]
+pub struct LiveReading {
+    pub timestamp: DateTime<Utc>,
+    pub pv_mw: i64,
+    pub grid_mw: Option<i64>,
+    pub storage_mw: Option<i64>,
+    pub load_mw: Option<i64>,
+    pub battery_soc: Option<u32>,
+}
+
+impl LiveReading {
+    fn from_state(state: &SystemState) -> Option<Self> {
+        Some(Self {
+            timestamp: state.last_update?,
+            pv_mw: state.pv_mw,
+            grid_mw: state.grid_mw,
+            storage_mw: state.storage_mw,
+            load_mw: state.load_mw,
+            battery_soc: state.battery_soc,
+        })
+    }
+
+    pub fn apply_to(&self, state: &mut SystemState) {
+        state.last_update = Some(self.timestamp);
+        state.pv_mw = self.pv_mw;
+        state.grid_mw = self.grid_mw;
+        state.storage_mw = self.storage_mw;
+        state.load_mw = self.load_mw;
+        state.battery_soc = self.battery_soc;
+    }
+}
+
+/// Values of each kind at a point in time
+type Sample = (DateTime<Utc>, Vec<(HistoryKind, i64)>);
+
+/// Averages live readings over a window, so that streaming doesn't add a row
+/// of history every second. Like history itself, each reading holds until the
+/// next one, and each average is stamped with the start of its window so that
+/// it holds for the window it covers.
+#[derive(Debug)]
+struct Downsampler {
+    window: TimeDelta,
+    started: Option<DateTime<Utc>>,
+    last: Option<Sample>,
+    /// Each value multiplied by the milliseconds it held for, and the total
+    /// milliseconds
+    integrals: BTreeMap<HistoryKind, (i64, i64)>,
+}
+
+impl Downsampler {
+    fn new(window: TimeDelta) -> Self {
+        Self {
+            window,
+            started: None,
+            last: None,
+            integrals: BTreeMap::new(),
+        }
+    }
+
+    /// Add a reading, returning the start of the window and its averages once
+    /// a whole window has passed
+    fn add(&mut self, timestamp: DateTime<Utc>, values: &[(HistoryKind, i64)]) -> Option<Sample> {
+        let started = *self.started.get_or_insert(timestamp);
+        if let Some((last_timestamp, last_values)) = self.last.replace((timestamp, values.to_vec()))
+        {
+            let millis = (timestamp - last_timestamp).num_milliseconds();
+            for (kind, value) in last_values {
+                let (integral, total) = self.integrals.entry(kind).or_default();
+                *integral += value * millis;
+                *total += millis;
+            }
+        }
+        if timestamp - started < self.window {
+            return None;
+        }
+        // this reading starts the next window
+        self.started = Some(timestamp);
+        let averages = std::mem::take(&mut self.integrals)
+            .into_iter()
+            .filter(|(_, (_, total))| *total > 0)
+            .map(|(kind, (integral, total))| (kind, integral / total))
+            .collect();
+        Some((started, averages))
+    }
+
+    /// Throw away the window in progress, so that it doesn't get mixed with
+    /// readings from after a gap
+    fn reset(&mut self) {
+        self.started = None;
+        self.last = None;
+        self.integrals.clear();
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct MeterReading {
+    pub measurement_type: MeterType,
+    pub timestamp: DateTime<Utc>,
+    pub phases: Vec<PhaseReading>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PhaseReading {
+    pub phase: String,
+    pub active_power_w: f64,
+    pub reactive_power_var: f64,
+    pub apparent_power_va: f64,
+    pub voltage_v: f64,
+    pub current_a: f64,
+    pub frequency_hz: Option<f64>,
+    pub power_factor: f64,
+}
+
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
 pub struct Inventory {
     pub battery_capacity: u32,
     pub num_batteries: usize,
+    pub batteries: Vec<Battery>,
     pub grid_state: Option<GridState>,
+    pub system_controller: Option<SystemController>,
+    pub dry_contacts: Vec<DryContact>,
+    pub generator: Option<Generator>,
+}
+
+impl Inventory {
+    /// Whether `other` differs from this only in when devices last reported,
+    /// which changes on nearly every poll
+    pub fn same_devices(&self, other: &Inventory) -> bool {
+        self.without_report_times() == other.without_report_times()
+    }
+
+    fn without_report_times(&self) -> Self {
+        let mut inventory = self.clone();
+        for battery in &mut inventory.batteries {
+            battery.last_report = DateTime::UNIX_EPOCH;
+        }
+        if let Some(controller) = inventory.system_controller.as_mut() {
+            controller.last_report = DateTime::UNIX_EPOCH;
+        }
+        inventory
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SystemController {
+    pub serial: String,
+    pub operating_state: String,
+    pub mains_admin_state: RelayState,
+    pub mains_oper_state: RelayState,
+    pub grid_mode: Option<GridState>,
+    pub relay_state_bitmask: u32,
+    pub communicating: bool,
+    pub last_report: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DryContact {
+    pub id: String,
+    pub status: RelayState,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Generator {
+    pub admin_state: Option<String>,
+    pub oper_state: Option<String>,
+    pub admin_mode: Option<String>,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Battery {
+    pub serial: String,
+    pub capacity_wh: u32,
+    pub percent_full: u32,
+    pub temperature_c: i32,
+    pub led_status: u32,
+    pub operating_state: String,
+    pub device_status: Vec<String>,
+    pub communicating: bool,
+    pub comm_level_sub_ghz: u32,
+    pub comm_level_2_4_ghz: u32,
+    pub last_report: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DeviceInfo {
+    pub serial: String,
+    pub part_number: String,
+    pub firmware: String,
+    pub build_time: Option<DateTime<Utc>>,
+    pub packages: Vec<DeviceInfoPackage>,
+    pub clock: DateTime<Utc>,
+    /// How far ahead of our clock the Envoy's clock was when fetched
+    pub clock_offset_secs: i64,
+    /// Since when the Envoy has answered every one of our info polls. This
+    /// is only what we've seen from here, not how long it's been running
+    pub reachable_since: DateTime<Utc>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DeviceInfoPackage {
+    pub name: String,
+    pub part_number: String,
+    pub version: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Inverter {
+    pub serial: String,
+    pub last_report: DateTime<Utc>,
+    pub last_report_watts: i64,
+    pub max_report_watts: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(u8)]
-enum HistoryKind {
+pub enum HistoryKind {
     Pv = 0,
     Grid = 1,
     Load = 2,
     Storage = 3,
+    Soc = 4,
+    ProductionToday = 5,
+    ConsumptionToday = 6,
 }
 
 impl TryFrom<u8> for HistoryKind {
@@ -46,23 +355,182 @@ impl TryFrom<u8> for HistoryKind {
             1 => Ok(Self::Grid),
             2 => Ok(Self::Load),
             3 => Ok(Self::Storage),
+            4 => Ok(Self::Soc),
+            5 => Ok(Self::ProductionToday),
+            6 => Ok(Self::ConsumptionToday),
             _ => anyhow::bail!("invalid discriminant"),
         }
     }
 }
 
-#[derive(Debug, Default)]
+impl std::str::FromStr for HistoryKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.query_name() == s)
+            .ok_or_else(|| anyhow::anyhow!("unknown series {s:?}"))
+    }
+}
+
+impl HistoryKind {
+    pub const ALL: [Self; 7] = [
+        Self::Pv,
+        Self::Grid,
+        Self::Load,
+        Self::Storage,
+        Self::Soc,
+        Self::ProductionToday,
+        Self::ConsumptionToday,
+    ];
+
+    pub fn series_name(&self) -> &'static str {
+        match self {
+            Self::Pv => "pv_mw",
+            Self::Grid => "grid_mw",
+            Self::Load => "load_mw",
+            Self::Storage => "storage_mw",
+            Self::Soc => "battery_soc",
+            Self::ProductionToday => "production_mwh_today",
+            Self::ConsumptionToday => "consumption_mwh_today",
+        }
+    }
+
+    /// A comma-separated list of query names; empty means every series
+    pub fn parse_list(series: &str) -> anyhow::Result<Vec<Self>> {
+        if series.is_empty() {
+            return Ok(Self::ALL.to_vec());
+        }
+        series.split(',').map(|s| s.trim().parse()).collect()
+    }
+
+    /// What this is called in history queries
+    pub fn query_name(&self) -> &'static str {
+        match self {
+            Self::Pv => "pv",
+            Self::Grid => "grid",
+            Self::Load => "load",
+            Self::Storage => "storage",
+            Self::Soc => "soc",
+            Self::ProductionToday => "production_today",
+            Self::ConsumptionToday => "consumption_today",
+        }
+    }
+
+    /// Whether this is a counter that the Envoy resets at midnight
+    pub fn is_daily_total(&self) -> bool {
+        matches!(self, Self::ProductionToday | Self::ConsumptionToday)
+    }
+
+    /// Whether this is power, and so can be integrated into energy
+    pub fn is_power(&self) -> bool {
+        matches!(self, Self::Pv | Self::Grid | Self::Load | Self::Storage)
+    }
+
+    /// The power series that this daily total should match when integrated
+    pub fn integrated_from(&self) -> Option<Self> {
+        match self {
+            Self::ProductionToday => Some(Self::Pv),
+            Self::ConsumptionToday => Some(Self::Load),
+            _ => None,
+        }
+    }
+}
+
+/// History for each kind of measurement this system has ever reported
+#[derive(Debug)]
 pub struct TimeSeriesData {
-    pv_mw: TimeSeriesRow,
-    storage_mw: TimeSeriesRow,
-    load_mw: TimeSeriesRow,
-    grid_mw: TimeSeriesRow,
+    rows: BTreeMap<HistoryKind, TimeSeriesRow>,
+    /// How long a reading counts for when there isn't a newer one
+    max_gap: TimeDelta,
 }
 
 impl TimeSeriesData {
+    fn new(max_gap: TimeDelta) -> Self {
+        Self {
+            rows: BTreeMap::new(),
+            max_gap,
+        }
+    }
+
+    /// The summary of the daily counter `kind`, checked against the power
+    /// readings that should add up to it
+    fn daily_total_summary(
+        &self,
+        kind: HistoryKind,
+        row: &TimeSeriesRow,
+        midnight: Option<DateTime<Utc>>,
+    ) -> DailyTotalSummary {
+        // the envoy's day starts when it resets the counter, which is at its
+        // midnight rather than necessarily ours
+        let day_start = row.last_reset().or(midnight);
+        let integrated_today = kind
+            .integrated_from()
+            .zip(day_start)
+            .and_then(|(power, day_start)| {
+                self.rows.get(&power)?.energy_since(day_start, self.max_gap)
+            })
+            .map(|energy| energy.energy_in_mwh);
+        row.daily_total_summary(day_start, integrated_today)
+    }
+
     #[tracing::instrument(skip_all)]
     fn load_from_db(&mut self, db: &mut rusqlite::Connection) -> anyhow::Result<()> {
         tracing::debug!("beginning load of historical data from database");
+        for rollup in Rollup::ALL {
+            let mut stmt = db.prepare(&format!(
+                "SELECT kind, timestamp, sum, count, min, max, integral, positive_integral, covered_secs FROM {}",
+                rollup.table()
+            ))?;
+            type RollupRow = (u8, i64, i64, i64, Option<i64>, Option<i64>);
+            // null in rollups written by older versions
+            type Integrals = (Option<i64>, Option<i64>, Option<i64>);
+            let rows = stmt.query_map([], |row| -> rusqlite::Result<(RollupRow, Integrals)> {
+                Ok((
+                    (
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                        row.get(5)?,
+                    ),
+                    (row.get(6)?, row.get(7)?, row.get(8)?),
+                ))
+            })?;
+            for row in rows {
+                let (
+                    (history_kind, timestamp, sum, count, min, max),
+                    (integral, positive_integral, covered_secs),
+                ) = row.context("error reading from sqlite")?;
+                let history_kind = HistoryKind::try_from(history_kind)?;
+                let timestamp = DateTime::<Utc>::from_timestamp(timestamp, 0)
+                    .ok_or_else(|| anyhow::anyhow!("invalid timestamp"))?;
+                // rollups from before time-weighting count every reading
+                // equally, as if they covered the whole bucket
+                let span_secs = (rollup.end(&timestamp) - timestamp).num_seconds();
+                let covered_secs = covered_secs.unwrap_or(span_secs);
+                let integral = integral.unwrap_or(sum / count.max(1) * covered_secs);
+                // and in those from before energy was split by direction,
+                // there's no telling which way it flowed, so
+                // `positive_integral` stays unknown
+                let stats = Statistics::from_parts(
+                    sum,
+                    count as usize,
+                    min,
+                    max,
+                    integral,
+                    positive_integral,
+                    covered_secs,
+                    span_secs,
+                );
+                self.rows
+                    .entry(history_kind)
+                    .or_default()
+                    .append_rollup(rollup, timestamp, stats);
+            }
+        }
         let mut stmt =
             db.prepare("SELECT kind, timestamp, value FROM history ORDER BY 1, 2 ASC")?;
         let mut loaded = 0;
@@ -87,44 +555,204 @@ impl TimeSeriesData {
             let (history_kind, timestamp, value) = row?;
             tracing::trace!(?history_kind, ?timestamp, value, "loading a row");
             loaded += 1;
-            match history_kind {
-                HistoryKind::Pv => self.pv_mw.append_raw(timestamp, value),
-                HistoryKind::Grid => self.grid_mw.append_raw(timestamp, value),
-                HistoryKind::Load => self.load_mw.append_raw(timestamp, value),
-                HistoryKind::Storage => self.storage_mw.append_raw(timestamp, value),
-            }
-        }
-        self.pv_mw.aggregate_historical();
-        self.grid_mw.aggregate_historical();
-        self.load_mw.aggregate_historical();
-        self.storage_mw.aggregate_historical();
-        tracing::debug!(?loaded, "finished load of historical data from database");
+            self.rows
+                .entry(history_kind)
+                .or_default()
+                .append_raw(timestamp, value);
+        }
+        drop(stmt);
+        let mut stmt = db.prepare("SELECT kind, date, value FROM daily_totals")?;
+        let rows = stmt.query_map([], |row| -> rusqlite::Result<(u8, String, i64)> {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
+        for row in rows {
+            let (history_kind, date, value) = row.context("error reading from sqlite")?;
+            let history_kind = HistoryKind::try_from(history_kind)?;
+            let date: NaiveDate = date.parse()?;
+            self.rows
+                .entry(history_kind)
+                .or_default()
+                .append_daily_total(date, value);
+        }
+        drop(stmt);
+        // anything that closed without being rolled up (because we stopped,
+        // or because the database predates rollups) gets rolled up now
+        let tx = db.transaction()?;
+        let mut backfilled = 0;
+        for (history_kind, row) in self.rows.iter_mut() {
+            for closed in row.aggregate_historical(self.max_gap) {
+                insert_rollup(&tx, *history_kind, &closed)?;
+                backfilled += 1;
+            }
+        }
+        tx.commit()?;
+        tracing::debug!(
+            ?loaded,
+            ?backfilled,
+            "finished load of historical data from database"
+        );
         Ok(())
     }
 }
 
-#[derive(Serialize, Debug)]
-pub struct HistoryResponse {
-    pv_mw: TimeSeriesSummary,
-    grid_mw: TimeSeriesSummary,
-    load_mw: TimeSeriesSummary,
-    storage_mw: TimeSeriesSummary,
+fn local_midnight() -> Option<DateTime<Utc>> {
+    Local::now()
+        .date_naive()
+        .and_time(NaiveTime::MIN)
+        .and_local_timezone(Local)
+        .earliest()
+        .map(|midnight| midnight.with_timezone(&Utc))
+}
+
+/// How far a daily counter can be from the total worked out from power
+/// readings before it's worth a warning: 5% or 100 Wh, whichever is more
+fn integrated_tolerance_mwh(today: i64) -> i64 {
+    (today.abs() / 20).max(100_000)
+}
+
+fn insert_rollup(
+    db: &rusqlite::Connection,
+    kind: HistoryKind,
+    (rollup, timestamp, stats): &ClosedBucket,
+) -> rusqlite::Result<usize> {
+    db.execute(
+        &format!(
+            "INSERT OR REPLACE INTO {}(kind, timestamp, sum, count, min, max, integral, positive_integral, covered_secs) VALUES(?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            rollup.table()
+        ),
+        (
+            kind as u8,
+            timestamp.timestamp(),
+            stats.sum,
+            stats.count as i64,
+            stats.min,
+            stats.max,
+            stats.integral,
+            stats.positive_integral,
+            stats.covered_secs,
+        ),
+    )
+}
+
+/// Upper bounds (in seconds) of the buckets for Envoy request latency
+pub const LATENCY_BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+#[derive(Debug, Default, Clone)]
+pub struct LatencyHistogram {
+    /// Requests falling in each of `LATENCY_BUCKETS` (not cumulative); slower
+    /// requests only show up in `count`
+    pub buckets: [u64; LATENCY_BUCKETS.len()],
+    pub sum_secs: f64,
+    pub count: u64,
+}
+
+impl LatencyHistogram {
+    fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|le| secs <= *le) {
+            self.buckets[i] += 1;
+        }
+        self.sum_secs += secs;
+        self.count += 1;
+    }
+}
+
+/// When we last managed (and failed) to fetch from a particular Envoy endpoint
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct EndpointStatus {
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_error: Option<DateTime<Utc>>,
+    pub last_error_message: Option<String>,
+    #[serde(skip)]
+    pub latency: LatencyHistogram,
+}
+
+impl EndpointStatus {
+    fn record<T>(&mut self, result: &anyhow::Result<T>, duration: Duration) {
+        self.latency.observe(duration);
+        match result {
+            Ok(_) => self.last_success = Some(Utc::now()),
+            Err(error) => {
+                self.last_error = Some(Utc::now());
+                self.last_error_message = Some(format!("{error:#}"));
+            }
+        }
+    }
 }
 
+/// How a background task has been doing, and where it is in its retry schedule
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct TaskStatus {
+    pub runs: u64,
+    pub failures: u64,
+    pub consecutive_failures: u32,
+    pub circuit_open: bool,
+    pub last_duration_secs: Option<f64>,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub next_run: Option<DateTime<Utc>>,
+}
+
+/// How often (and how slowly) we've had to establish a session with the Envoy
+#[derive(Debug, Default)]
+pub struct AuthStats {
+    pub count: u64,
+    pub last_duration: Option<Duration>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(transparent)]
+pub struct HistoryResponse(BTreeMap<&'static str, SeriesSummary>);
+
 pub struct AppState {
     pub client: reqwest::Client,
+    pub tokens: TokenManager,
+    pub token_expiry_warning: TimeDelta,
+    /// When the Envoy first started rejecting our token, if it currently is
+    pub auth_failed_since: RwLock<Option<DateTime<Utc>>>,
+    dialect_override: Option<Dialect>,
+    pub dialect: RwLock<Option<Dialect>>,
+    legacy_username: String,
+    legacy_password: Option<String>,
+    envoy_serial: Option<String>,
+    pub session_auth: bool,
+    session_established: tokio::sync::Mutex<bool>,
+    pub auth_stats: RwLock<AuthStats>,
+    pub endpoints: RwLock<BTreeMap<&'static str, EndpointStatus>>,
+    pub tasks: RwLock<BTreeMap<&'static str, TaskStatus>>,
+    pub refresh: RefreshCoordinator,
+    pub events: broadcast::Sender<StateEvent>,
+    /// Whether the live stream is currently feeding us readings (and so
+    /// writing history in place of polling)
+    pub streaming: AtomicBool,
+    live_samples: Mutex<Downsampler>,
     pub system_state: RwLock<SystemState>,
     pub inventory: RwLock<Inventory>,
+    pub device_info: RwLock<Option<DeviceInfo>>,
+    /// Whether the last info poll failed, since which `reachable_since` restarts
+    device_info_failed: AtomicBool,
+    pub inverters: RwLock<Vec<Inverter>>,
     pub time_series: RwLock<TimeSeriesData>,
     pub db: Arc<Mutex<rusqlite::Connection>>,
+    retention: Retention,
+    /// Daily counters that currently disagree with their integrated totals,
+    /// so that it's only logged when that changes
+    disagreeing_totals: Mutex<BTreeSet<HistoryKind>>,
+    /// Where `db` lives, for anything that wants a connection of its own
+    pub state_path: std::path::PathBuf,
+    /// The same as `time_series` uses, for exports that read history straight
+    /// from the database
+    pub history_max_gap: TimeDelta,
 }
 
 impl AppState {
-    pub fn new<P: AsRef<Path>>(store_path: P) -> anyhow::Result<Self> {
-        let store_path = store_path.as_ref();
+    pub fn new(args: &Args, tokens: TokenManager) -> anyhow::Result<Self> {
+        let store_path = args.state_path.as_path();
         tracing::debug!("initializing network client");
         let client = reqwest::Client::builder()
             .tls_backend_rustls()
+            // session cookies from /auth/check_jwt
+            .cookie_store(true)
             // envoy makes up totally bogus certs
             .danger_accept_invalid_certs(true)
             .danger_accept_invalid_hostnames(true)
@@ -132,84 +760,710 @@ impl AppState {
             .build()?;
         let system_state = RwLock::new(SystemState::default());
         let inventory = RwLock::new(Inventory::default());
-        let mut time_series = TimeSeriesData::default();
+        let device_info = RwLock::new(None);
+        let inverters = RwLock::new(Vec::new());
+        let mut time_series = TimeSeriesData::new(args.history_max_gap());
         let mut db = rusqlite::Connection::open(store_path)?;
         tracing::debug!(?store_path, "initializing time-series database");
-        db.execute_batch(
-            r#"
-            CREATE TABLE IF NOT EXISTS history(
-                kind INTEGER NOT NULL,
-                timestamp BIGINT NOT NULL,
-                value BIGINT NOT NULL,
-                PRIMARY KEY (kind, timestamp)
-            );
-            CREATE INDEX IF NOT EXISTS idx_history_on_timestamp ON history(timestamp);
-            "#,
-        )?;
         db.pragma_update(None, "journal_mode", "WAL")?;
+        migrations::migrate(&mut db, store_path)?;
         time_series.load_from_db(&mut db)?;
         let time_series = RwLock::new(time_series);
         let db = Arc::new(Mutex::new(db));
         Ok(Self {
             client,
+            tokens,
+            token_expiry_warning: args.token_expiry_warning(),
+            auth_failed_since: RwLock::new(None),
+            dialect_override: args.envoy_dialect,
+            dialect: RwLock::new(args.envoy_dialect),
+            legacy_username: args.legacy_username.clone(),
+            legacy_password: args.legacy_password.clone(),
+            envoy_serial: args.envoy_serial.clone(),
+            session_auth: args.session_auth,
+            session_established: tokio::sync::Mutex::new(false),
+            auth_stats: RwLock::new(AuthStats::default()),
+            endpoints: RwLock::new(BTreeMap::new()),
+            tasks: RwLock::new(BTreeMap::new()),
+            refresh: RefreshCoordinator::new(args.refresh_min_interval()),
+            events: broadcast::channel(EVENT_BUFFER).0,
+            streaming: AtomicBool::new(false),
+            live_samples: Mutex::new(Downsampler::new(args.stream_downsample())),
             system_state,
             inventory,
+            device_info_failed: AtomicBool::new(false),
+            device_info,
+            inverters,
             time_series,
             db,
+            retention: Retention::from_args(args),
+            disagreeing_totals: Mutex::new(BTreeSet::new()),
+            state_path: args.state_path.clone(),
+            history_max_gap: args.history_max_gap(),
+        })
+    }
+
+    /// Which API the Envoy speaks, detecting it from the firmware version if
+    /// we weren't told
+    pub async fn dialect(&self, base_url: &Url) -> anyhow::Result<Dialect> {
+        if let Some(dialect) = *self.dialect.read().await {
+            return Ok(dialect);
+        }
+        let info = self
+            .fetch_device_info(base_url)
+            .await
+            .context("unable to detect envoy firmware version")?;
+        self.update_device_info(info).await;
+        self.dialect
+            .read()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("unable to detect envoy dialect"))
+    }
+
+    async fn legacy_auth(&self) -> anyhow::Result<EnvoyAuth> {
+        let password = match self.legacy_password.as_ref() {
+            Some(password) => password.clone(),
+            None => {
+                let serial = match self.envoy_serial.as_ref() {
+                    Some(serial) => serial.clone(),
+                    None => self
+                        .device_info
+                        .read()
+                        .await
+                        .as_ref()
+                        .map(|info| info.serial.clone())
+                        .ok_or_else(|| anyhow::anyhow!("envoy serial number is not known"))?,
+                };
+                serial[serial.len().saturating_sub(6)..].to_owned()
+            }
+        };
+        Ok(EnvoyAuth::Digest {
+            username: self.legacy_username.clone(),
+            password,
         })
     }
 
+    /// Make a request to the Envoy, re-authenticating (and getting a new
+    /// token, if we can) and retrying once if the Envoy rejects us
+    pub async fn with_envoy_auth<T, F, Fut>(&self, base_url: &Url, f: F) -> anyhow::Result<T>
+    where
+        F: Fn(Dialect, EnvoyAuth) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let result = match self.dialect(base_url).await {
+            Ok(Dialect::Legacy) => match self.legacy_auth().await {
+                Ok(auth) => f(Dialect::Legacy, auth).await,
+                Err(error) => Err(error),
+            },
+            Ok(Dialect::Modern) => self.with_envoy_token(base_url, &f).await,
+            Err(error) => Err(error),
+        };
+        self.record_envoy_result(&result).await;
+        result
+    }
+
+    async fn with_envoy_token<T, F, Fut>(&self, base_url: &Url, f: &F) -> anyhow::Result<T>
+    where
+        F: Fn(Dialect, EnvoyAuth) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let token = self.tokens.require_current().await?;
+        match self.request_with_token(base_url, &token, false, f).await {
+            Err(error) if envoy_api::is_unauthorized(&error) => {
+                // a rejected session might just mean that it expired
+                let retried = if self.session_auth {
+                    tracing::debug!("envoy rejected our session; re-authenticating");
+                    self.request_with_token(base_url, &token, true, f).await
+                } else {
+                    Err(error)
+                };
+                match retried {
+                    Err(error)
+                        if envoy_api::is_unauthorized(&error) && self.tokens.can_refresh() =>
+                    {
+                        tracing::warn!("envoy rejected our token; fetching a new one");
+                        match self.tokens.refresh(&token).await {
+                            Ok(new_token) => {
+                                self.request_with_token(base_url, &new_token, true, f).await
+                            }
+                            Err(refresh_error) => {
+                                tracing::error!(
+                                    ?refresh_error,
+                                    "unable to fetch a new envoy token"
+                                );
+                                Err(error)
+                            }
+                        }
+                    }
+                    r => r,
+                }
+            }
+            r => r,
+        }
+    }
+
+    async fn request_with_token<T, F, Fut>(
+        &self,
+        base_url: &Url,
+        token: &EnvoyToken,
+        new_session: bool,
+        f: &F,
+    ) -> anyhow::Result<T>
+    where
+        F: Fn(Dialect, EnvoyAuth) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        if !self.session_auth {
+            return f(Dialect::Modern, EnvoyAuth::Bearer(token.clone())).await;
+        }
+        let mut session_guard = self.session_established.lock().await;
+        if new_session || !*session_guard {
+            *session_guard = false;
+            let (result, elapsed) =
+                envoy_api::timed(envoy_api::check_jwt(base_url, token, &self.client)).await;
+            tracing::debug!(?elapsed, ok = result.is_ok(), "authenticated to envoy");
+            self.endpoints
+                .write()
+                .await
+                .entry("/auth/check_jwt")
+                .or_default()
+                .record(&result, elapsed);
+            let mut stats = self.auth_stats.write().await;
+            stats.count += 1;
+            stats.last_duration = Some(elapsed);
+            drop(stats);
+            result?;
+            *session_guard = true;
+        }
+        drop(session_guard);
+        f(Dialect::Modern, EnvoyAuth::Session).await
+    }
+
+    /// Track whether the Envoy is accepting our token, based on the outcome of
+    /// a request to it
+    async fn record_envoy_result<T>(&self, result: &anyhow::Result<T>) {
+        match result {
+            Ok(_) => {
+                *self.auth_failed_since.write().await = None;
+            }
+            Err(error) if envoy_api::is_unauthorized(error) => {
+                let mut guard = self.auth_failed_since.write().await;
+                if guard.is_none() {
+                    *guard = Some(Utc::now());
+                }
+            }
+            Err(_) => {}
+        }
+    }
+
     pub async fn history(&self) -> HistoryResponse {
         let ts = self.time_series.read().await;
-        HistoryResponse {
-            pv_mw: ts.pv_mw.summary(),
-            grid_mw: ts.grid_mw.summary(),
-            load_mw: ts.load_mw.summary(),
-            storage_mw: ts.storage_mw.summary(),
+        let midnight = local_midnight();
+        HistoryResponse(
+            ts.rows
+                .iter()
+                .map(|(kind, row)| {
+                    let summary = if kind.is_daily_total() {
+                        SeriesSummary::DailyTotal(ts.daily_total_summary(*kind, row, midnight))
+                    } else {
+                        SeriesSummary::Statistics(Box::new(row.summary(kind.is_power())))
+                    };
+                    (kind.series_name(), summary)
+                })
+                .collect(),
+        )
+    }
+
+    pub async fn record_endpoints(&self, outcomes: &[EndpointOutcome]) {
+        let mut guard = self.endpoints.write().await;
+        for outcome in outcomes {
+            guard
+                .entry(outcome.endpoint)
+                .or_default()
+                .record(&outcome.result, outcome.duration);
+        }
+    }
+
+    /// Make a request to `endpoint` of the Envoy, recording how it went
+    pub async fn track<T>(
+        &self,
+        endpoint: &'static str,
+        request: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let (result, duration) = envoy_api::timed(request).await;
+        self.endpoints
+            .write()
+            .await
+            .entry(endpoint)
+            .or_default()
+            .record(&result, duration);
+        result
+    }
+
+    pub async fn fetch_device_info(&self, base_url: &Url) -> anyhow::Result<DeviceInfo> {
+        self.track("/info", envoy_api::fetch_info(base_url, &self.client))
+            .await
+    }
+
+    /// Bucketed history for each of `kinds` between `from` and `to`
+    pub async fn query_history(
+        &self,
+        kinds: &[HistoryKind],
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        step: Step,
+        aggregation: Aggregation,
+    ) -> anyhow::Result<BTreeMap<&'static str, Vec<HistoryPoint>>> {
+        anyhow::ensure!(from <= to, "from must not be after to");
+        let max_gap = self.time_series.read().await.max_gap;
+        let mut result = BTreeMap::new();
+        for kind in kinds {
+            let history = self.history_points(*kind, from, to).await?;
+            anyhow::ensure!(
+                history.size() <= MAX_HISTORY_POINTS,
+                "more than {MAX_HISTORY_POINTS} {} readings between from and to; ask for a shorter range",
+                kind.query_name()
+            );
+            result.insert(
+                kind.query_name(),
+                time_series::bucketize(&history, step, aggregation, max_gap),
+            );
+        }
+        Ok(result)
+    }
+
+    /// Raw history, from memory if we still have it there and otherwise from
+    /// the database (at most one more than [`MAX_HISTORY_POINTS`] of it). Once
+    /// the raw data for `from` has expired, this is the finest rollup that
+    /// still goes back that far instead.
+    async fn history_points(
+        &self,
+        kind: HistoryKind,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> anyhow::Result<History> {
+        let ts = self.time_series.read().await;
+        let row = ts.rows.get(&kind);
+        if let Some(row) = row
+            && row.covers(from)
+        {
+            return Ok(History::Raw(row.points(from, to)));
+        }
+        let now = Utc::now();
+        let kept = |keep: Option<TimeDelta>| keep.is_none_or(|keep| now - keep <= from);
+        if !kept(self.retention.raw) {
+            // every rollup is kept in memory as well as in the database
+            let rollup = Rollup::ALL
+                .into_iter()
+                .find(|rollup| kept(self.retention.rollup(*rollup)))
+                .unwrap_or(Rollup::Month);
+            let buckets = row.map(|row| row.rollups(rollup, from, to));
+            return Ok(History::Rollup(rollup, buckets.unwrap_or_default()));
+        }
+        drop(ts);
+
+        let db = self.db.clone();
+        let points = tokio::task::spawn_blocking(move || -> anyhow::Result<BTreeMap<DateTime<Utc>, i64>> {
+            let db = db.lock().unwrap();
+            let mut stmt = db.prepare(
+                "SELECT timestamp, value FROM history WHERE kind = ?1 AND timestamp BETWEEN ?2 AND ?3 ORDER BY timestamp ASC LIMIT ?4",
+            )?;
+            let rows = stmt
+                .query_map(
+                    (kind as u8, from.timestamp(), to.timestamp(), MAX_HISTORY_POINTS as i64 + 1),
+                    |row| -> rusqlite::Result<(i64, i64)> { Ok((row.get(0)?, row.get(1)?)) },
+                )?
+                .map(|r| {
+                    let (timestamp, value) = r.context("error reading from sqlite")?;
+                    let timestamp = DateTime::<Utc>::from_timestamp(timestamp, 0)
+                        .ok_or_else(|| anyhow::anyhow!("invalid timestamp"))?;
+                    Ok((timestamp, value))
+                })
+                .collect::<anyhow::Result<BTreeMap<_, _>>>()?;
+            Ok(rows)
+        })
+        .await??;
+        Ok(History::Raw(points))
+    }
+
+    /// Bucketed state of charge of each of the batteries in `serials` between
+    /// `from` and `to`
+    pub async fn query_battery_history(
+        &self,
+        serials: &[String],
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        step: Step,
+        aggregation: Aggregation,
+    ) -> anyhow::Result<BTreeMap<String, Vec<HistoryPoint>>> {
+        anyhow::ensure!(from <= to, "from must not be after to");
+        let max_gap = self.time_series.read().await.max_gap;
+        let mut result = BTreeMap::new();
+        for serial in serials {
+            let db = self.db.clone();
+            let query_serial = serial.clone();
+            let points = tokio::task::spawn_blocking(move || -> anyhow::Result<BTreeMap<DateTime<Utc>, i64>> {
+                let db = db.lock().unwrap();
+                let mut stmt = db.prepare(
+                    "SELECT timestamp, soc FROM battery_history WHERE serial = ?1 AND timestamp BETWEEN ?2 AND ?3 ORDER BY timestamp ASC LIMIT ?4",
+                )?;
+                let rows = stmt
+                    .query_map(
+                        (&query_serial, from.timestamp(), to.timestamp(), MAX_HISTORY_POINTS as i64 + 1),
+                        |row| -> rusqlite::Result<(i64, i64)> { Ok((row.get(0)?, row.get(1)?)) },
+                    )?
+                    .map(|r| {
+                        let (timestamp, soc) = r.context("error reading from sqlite")?;
+                        let timestamp = DateTime::<Utc>::from_timestamp(timestamp, 0)
+                            .ok_or_else(|| anyhow::anyhow!("invalid timestamp"))?;
+                        Ok((timestamp, soc))
+                    })
+                    .collect::<anyhow::Result<BTreeMap<_, _>>>()?;
+                Ok(rows)
+            })
+            .await??;
+            anyhow::ensure!(
+                points.len() <= MAX_HISTORY_POINTS,
+                "more than {MAX_HISTORY_POINTS} readings of battery {serial} between from and to; ask for a shorter range"
+            );
+            result.insert(
+                serial.clone(),
+                time_series::bucketize(&History::Raw(points), step, aggregation, max_gap),
+            );
+        }
+        Ok(result)
+    }
+
+    /// Summaries of the daily counters alone, without the rest of `history`
+    pub async fn daily_total_summaries(&self) -> Vec<(HistoryKind, DailyTotalSummary)> {
+        let ts = self.time_series.read().await;
+        let midnight = local_midnight();
+        ts.rows
+            .iter()
+            .filter(|(kind, _)| kind.is_daily_total())
+            .map(|(kind, row)| (*kind, ts.daily_total_summary(*kind, row, midnight)))
+            .collect()
+    }
+
+    /// Warn when a daily counter stops agreeing with the power readings that
+    /// should add up to it, and note when it agrees again
+    async fn check_integrated_totals(&self) {
+        for (kind, summary) in self.daily_total_summaries().await {
+            let (Some(today), Some(delta)) = (summary.today(), summary.integrated_delta()) else {
+                continue;
+            };
+            let tolerance = integrated_tolerance_mwh(today);
+            let disagrees = delta.abs() > tolerance;
+            let mut disagreeing = self.disagreeing_totals.lock().unwrap();
+            if disagrees && disagreeing.insert(kind) {
+                tracing::warn!(
+                    series = kind.query_name(),
+                    today,
+                    delta,
+                    tolerance,
+                    "daily counter disagrees with the power readings integrated since it reset"
+                );
+            } else if !disagrees && disagreeing.remove(&kind) {
+                tracing::info!(
+                    series = kind.query_name(),
+                    today,
+                    delta,
+                    "daily counter agrees with the integrated power readings again"
+                );
+            }
         }
     }
 
-    pub async fn update_state(&self, new_state: SystemState) {
+    /// The largest swing in PV or load power between the last two readings
+    pub async fn recent_change_mw(&self) -> i64 {
+        let ts = self.time_series.read().await;
+        [HistoryKind::Pv, HistoryKind::Load]
+            .iter()
+            .filter_map(|kind| ts.rows.get(kind)?.last_change())
+            .map(i64::abs)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// `counters_fetched` says whether the daily counters in `new_state` are
+    /// fresh, or just carried over from before because fetching them failed
+    pub async fn update_state(&self, new_state: SystemState, counters_fetched: bool) {
         let Some(dt) = new_state.last_update else {
             return;
         };
-        let mut time_series_guard = self.time_series.write().await;
-        time_series_guard.pv_mw.append(dt, new_state.pv_mw);
-        time_series_guard.grid_mw.append(dt, new_state.grid_mw);
-        time_series_guard.load_mw.append(dt, new_state.load_mw);
-        time_series_guard
-            .storage_mw
-            .append(dt, new_state.storage_mw);
-        drop(time_series_guard);
+        let mut new_state = new_state;
+        let values = new_state.history_values();
+        let daily_totals = if counters_fetched {
+            new_state.daily_total_values()
+        } else {
+            Vec::new()
+        };
 
         let mut state_guard = self.system_state.write().await;
-        *state_guard = new_state.clone();
+        let previous_update = state_guard.last_update;
+        // don't overwrite a newer reading from the live stream
+        if previous_update.is_some_and(|previous| previous > dt)
+            && let Some(live) = LiveReading::from_state(&state_guard)
+        {
+            live.apply_to(&mut new_state);
+        }
+        let delta = new_state.delta_from(&state_guard);
+        *state_guard = new_state;
+        drop(state_guard);
+        self.publish_delta(delta);
+
+        if !daily_totals.is_empty() {
+            self.record_daily_totals(dt, daily_totals).await;
+            self.check_integrated_totals().await;
+        }
+
+        // if only the slower endpoints answered, there's no new reading to
+        // add to history; while streaming, the stream takes care of it
+        if previous_update.is_some_and(|previous| previous >= dt)
+            || self.streaming.load(Ordering::Relaxed)
+        {
+            return;
+        }
+
+        self.record_history(dt, values).await;
+    }
+
+    pub async fn apply_live_reading(&self, reading: LiveReading) {
+        let mut state_guard = self.system_state.write().await;
+        // the envoy doesn't necessarily update as often as we ask
+        if state_guard
+            .last_update
+            .is_some_and(|last_update| last_update >= reading.timestamp)
+        {
+            return;
+        }
+        let previous = state_guard.clone();
+        reading.apply_to(&mut state_guard);
+        let values = state_guard.history_values();
+        let delta = state_guard.delta_from(&previous);
         drop(state_guard);
+        self.publish_delta(delta);
+
+        let averages = self
+            .live_samples
+            .lock()
+            .unwrap()
+            .add(reading.timestamp, &values);
+        if let Some((started, averages)) = averages {
+            self.record_history(started, averages).await;
+        }
+    }
+
+    /// Note that the live stream has stopped, returning whether it had been
+    /// running
+    pub fn stop_streaming(&self) -> bool {
+        self.live_samples.lock().unwrap().reset();
+        self.streaming.swap(false, Ordering::Relaxed)
+    }
+
+    /// Daily counters are recorded from every poll that's newer than the last
+    /// one recorded, whether or not the live stream is running
+    async fn record_daily_totals(&self, dt: DateTime<Utc>, values: Vec<(HistoryKind, i64)>) {
+        let time_series_guard = self.time_series.read().await;
+        let values: Vec<_> = values
+            .into_iter()
+            .filter(|(kind, _)| {
+                time_series_guard
+                    .rows
+                    .get(kind)
+                    .and_then(|row| row.latest())
+                    .is_none_or(|(latest, _)| latest < dt)
+            })
+            .collect();
+        drop(time_series_guard);
+        if !values.is_empty() {
+            self.record_history(dt, values).await;
+        }
+    }
+
+    async fn record_history(&self, dt: DateTime<Utc>, values: Vec<(HistoryKind, i64)>) {
+        let mut time_series_guard = self.time_series.write().await;
+        let mut closed = Vec::new();
+        let mut totals = Vec::new();
+        let max_gap = time_series_guard.max_gap;
+        for (kind, value) in &values {
+            let row = time_series_guard.rows.entry(*kind).or_default();
+            if kind.is_daily_total()
+                && let Some((date, total)) = row.close_day(dt, *value)
+            {
+                totals.push((*kind, date, total));
+            }
+            closed.extend(
+                row.append(dt, *value, max_gap)
+                    .into_iter()
+                    .map(|c| (*kind, c)),
+            );
+        }
+        drop(time_series_guard);
 
         let db = self.db.clone();
         if let Err(err) = tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
             let mut db = db.lock().unwrap();
             let tx = db.transaction()?;
-            tx.execute(
-                "INSERT INTO history(kind, timestamp, value) VALUES(?1, ?2, ?3)",
-                (HistoryKind::Pv as u8, dt.timestamp(), new_state.pv_mw),
-            )?;
-            tx.execute(
-                "INSERT INTO history(kind, timestamp, value) VALUES(?1, ?2, ?3)",
-                (HistoryKind::Grid as u8, dt.timestamp(), new_state.grid_mw),
-            )?;
-            tx.execute(
-                "INSERT INTO history(kind, timestamp, value) VALUES(?1, ?2, ?3)",
-                (HistoryKind::Load as u8, dt.timestamp(), new_state.load_mw),
-            )?;
-            tx.execute(
-                "INSERT INTO history(kind, timestamp, value) VALUES(?1, ?2, ?3)",
-                (
-                    HistoryKind::Storage as u8,
-                    dt.timestamp(),
-                    new_state.storage_mw,
-                ),
-            )?;
+            for (kind, value) in values {
+                tx.execute(
+                    "INSERT INTO history(kind, timestamp, value) VALUES(?1, ?2, ?3)",
+                    (kind as u8, dt.timestamp(), value),
+                )?;
+            }
+            for (kind, closed) in closed {
+                insert_rollup(&tx, kind, &closed)?;
+            }
+            for (kind, date, total) in totals {
+                tx.execute(
+                    "INSERT OR REPLACE INTO daily_totals(kind, date, value) VALUES(?1, ?2, ?3)",
+                    (kind as u8, date.to_string(), total),
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+        {
+            tracing::warn!(?err, "failed writing to database");
+        }
+    }
+
+    /// Let anyone watching `/events` know what changed; it's fine if nobody is
+    fn publish_delta(&self, delta: serde_json::Map<String, serde_json::Value>) {
+        if !delta.is_empty() {
+            let _ = self.events.send(StateEvent::State(delta));
+        }
+    }
+
+    pub async fn snapshot_event(&self) -> StateEvent {
+        StateEvent::Snapshot {
+            state: Box::new(self.system_state.read().await.clone()),
+            inventory: self.inventory.read().await.clone(),
+        }
+    }
+
+    pub async fn update_inventory(&self, new_inventory: Inventory) {
+        let batteries = new_inventory.batteries.clone();
+
+        let mut inventory_guard = self.inventory.write().await;
+        let previous = std::mem::replace(&mut *inventory_guard, new_inventory.clone());
+        drop(inventory_guard);
+        if previous.grid_state != new_inventory.grid_state {
+            let _ = self
+                .events
+                .send(StateEvent::GridState(new_inventory.grid_state));
+        }
+        if !previous.same_devices(&new_inventory) {
+            let _ = self.events.send(StateEvent::Inventory(new_inventory));
+        }
+
+        self.record_battery_history(batteries).await;
+    }
+
+    /// Whether the last inventory found any batteries to keep track of
+    pub async fn has_batteries(&self) -> bool {
+        !self.inventory.read().await.batteries.is_empty()
+    }
+
+    /// Update the batteries alone from a fresh inventory, leaving the rest of
+    /// it to the next full inventory poll
+    pub async fn update_batteries(&self, new_inventory: Inventory) {
+        let batteries = new_inventory.batteries;
+
+        let mut inventory_guard = self.inventory.write().await;
+        let previous = inventory_guard.clone();
+        inventory_guard.battery_capacity = new_inventory.battery_capacity;
+        inventory_guard.num_batteries = new_inventory.num_batteries;
+        inventory_guard.batteries = batteries.clone();
+        let updated = inventory_guard.clone();
+        drop(inventory_guard);
+        if !previous.same_devices(&updated) {
+            let _ = self.events.send(StateEvent::Inventory(updated));
+        }
+
+        self.record_battery_history(batteries).await;
+    }
+
+    async fn record_battery_history(&self, batteries: Vec<Battery>) {
+        let db = self.db.clone();
+        if let Err(err) = tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            let mut db = db.lock().unwrap();
+            let tx = db.transaction()?;
+            for battery in &batteries {
+                tx.execute(
+                    "INSERT OR IGNORE INTO battery_history(serial, timestamp, soc) VALUES(?1, ?2, ?3)",
+                    (
+                        &battery.serial,
+                        battery.last_report.timestamp(),
+                        battery.percent_full,
+                    ),
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+        {
+            tracing::warn!(?err, "failed writing to database");
+        }
+    }
+
+    pub async fn update_device_info(&self, mut new_info: DeviceInfo) {
+        let mut info_guard = self.device_info.write().await;
+        if let Some(old_info) = info_guard.as_ref() {
+            if old_info.firmware != new_info.firmware {
+                tracing::warn!(
+                    old_firmware = old_info.firmware,
+                    new_firmware = new_info.firmware,
+                    "envoy firmware changed"
+                );
+            }
+            if !self.device_info_failed.swap(false, Ordering::Relaxed) {
+                new_info.reachable_since = old_info.reachable_since;
+            }
+        }
+        if self.dialect_override.is_none() {
+            let dialect = Dialect::from_firmware(&new_info.firmware);
+            let mut dialect_guard = self.dialect.write().await;
+            if *dialect_guard != Some(dialect) {
+                tracing::info!(
+                    firmware = new_info.firmware,
+                    ?dialect,
+                    "detected envoy dialect"
+                );
+                *dialect_guard = Some(dialect);
+            }
+        }
+        *info_guard = Some(new_info);
+    }
+
+    /// The Envoy didn't answer an info poll, so it may have restarted
+    pub fn device_info_failed(&self) {
+        self.device_info_failed.store(true, Ordering::Relaxed);
+    }
+
+    pub async fn update_inverters(&self, new_inverters: Vec<Inverter>) {
+        let mut inverters_guard = self.inverters.write().await;
+        *inverters_guard = new_inverters.clone();
+        drop(inverters_guard);
+
+        let db = self.db.clone();
+        if let Err(err) = tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            let mut db = db.lock().unwrap();
+            let tx = db.transaction()?;
+            for inverter in &new_inverters {
+                // inverters only report every few minutes, so most polls will
+                // see a report we've already stored
+                tx.execute(
+                    "INSERT OR IGNORE INTO inverter_history(serial, timestamp, watts) VALUES(?1, ?2, ?3)",
+                    (
+                        &inverter.serial,
+                        inverter.last_report.timestamp(),
+                        inverter.last_report_watts,
+                    ),
+                )?;
+            }
             tx.commit()?;
             Ok(())
         })
@@ -221,21 +1475,47 @@ impl AppState {
 
     pub async fn maintain(&self) {
         let mut time_series_guard = self.time_series.write().await;
-        time_series_guard.pv_mw.maintain();
-        time_series_guard.grid_mw.maintain();
-        time_series_guard.load_mw.maintain();
-        time_series_guard.storage_mw.maintain();
+        for row in time_series_guard.rows.values_mut() {
+            row.maintain(&self.retention);
+        }
         drop(time_series_guard);
         let db = self.db.clone();
-        const HISTORY_RETAIN: TimeDelta = TimeDelta::days(14);
-        let threshold = Utc::now() - HISTORY_RETAIN;
+        let now = Utc::now();
+        let raw_threshold = self.retention.raw.map(|keep| now - keep);
+        let rollup_thresholds: Vec<_> = Rollup::ALL
+            .into_iter()
+            .filter_map(|rollup| Some((rollup, now - self.retention.rollup(rollup)?)))
+            .collect();
         if let Err(err) = tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
             let mut db = db.lock().unwrap();
             let tx = db.transaction()?;
-            tx.execute(
-                "DELETE FROM history WHERE timestamp < ?1",
-                [threshold.timestamp()],
-            )?;
+            if let Some(threshold) = raw_threshold {
+                tx.execute(
+                    "DELETE FROM history WHERE timestamp < ?1",
+                    [threshold.timestamp()],
+                )?;
+                tx.execute(
+                    "DELETE FROM inverter_history WHERE timestamp < ?1",
+                    [threshold.timestamp()],
+                )?;
+                tx.execute(
+                    "DELETE FROM battery_history WHERE timestamp < ?1",
+                    [threshold.timestamp()],
+                )?;
+            }
+            for (rollup, threshold) in rollup_thresholds {
+                tx.execute(
+                    &format!("DELETE FROM {} WHERE timestamp < ?1", rollup.table()),
+                    [threshold.timestamp()],
+                )?;
+                // daily totals go along with the daily rollups
+                if rollup == Rollup::Day {
+                    tx.execute(
+                        "DELETE FROM daily_totals WHERE date < ?1",
+                        [threshold.date_naive().to_string()],
+                    )?;
+                }
+            }
             tx.commit()?;
             Ok(())
         })
@@ -245,3 +1525,65 @@ impl AppState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inventory_ignores_report_times() {
+        let battery = Battery {
+            serial: "122107012345".to_owned(),
+            capacity_wh: 3360,
+            percent_full: 43,
+            temperature_c: 29,
+            led_status: 17,
+            operating_state: "ENCHG_STATE_READY".to_owned(),
+            device_status: vec!["envoy.global.ok".to_owned()],
+            communicating: true,
+            comm_level_sub_ghz: 5,
+            comm_level_2_4_ghz: 4,
+            last_report: DateTime::from_timestamp(1692806400, 0).unwrap(),
+        };
+        let inventory = Inventory {
+            battery_capacity: 3360,
+            num_batteries: 1,
+            batteries: vec![battery],
+            ..Inventory::default()
+        };
+
+        let mut reported = inventory.clone();
+        reported.batteries[0].last_report += TimeDelta::minutes(15);
+        assert!(inventory.same_devices(&reported));
+
+        let mut discharged = reported.clone();
+        discharged.batteries[0].percent_full = 42;
+        assert!(!inventory.same_devices(&discharged));
+    }
+
+    #[test]
+    fn downsampler_weights_by_time() {
+        let start = DateTime::from_timestamp(1692806400, 0).unwrap();
+        let at = |secs| start + TimeDelta::seconds(secs);
+        let mut downsampler = Downsampler::new(TimeDelta::seconds(60));
+        assert_eq!(downsampler.add(at(0), &[(HistoryKind::Pv, 1000)]), None);
+        // a quick blip shouldn't count as much as the reading that held
+        assert_eq!(downsampler.add(at(50), &[(HistoryKind::Pv, 4000)]), None);
+        assert_eq!(
+            downsampler.add(at(60), &[(HistoryKind::Pv, 2000)]),
+            Some((at(0), vec![(HistoryKind::Pv, 1500)]))
+        );
+        assert_eq!(
+            downsampler.add(at(120), &[(HistoryKind::Pv, 0)]),
+            Some((at(60), vec![(HistoryKind::Pv, 2000)]))
+        );
+
+        // after a reset, readings from before don't hold into the next window
+        downsampler.reset();
+        assert_eq!(downsampler.add(at(600), &[(HistoryKind::Pv, 500)]), None);
+        assert_eq!(
+            downsampler.add(at(660), &[(HistoryKind::Pv, 0)]),
+            Some((at(600), vec![(HistoryKind::Pv, 500)]))
+        );
+    }
+}
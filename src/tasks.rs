@@ -1,12 +1,68 @@
+use chrono::{TimeDelta, Utc};
 use tokio::sync::broadcast;
 
 use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 
 use crate::args::Args;
-use crate::envoy_api;
+use crate::envoy_api::{self, Dialect};
+use crate::solar;
 use crate::state::AppState;
 
+/// How soon to run a task again after it fails
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// After this many consecutive failures, stop retrying quickly and only
+    /// try again every `circuit_breaker_cooldown`
+    pub circuit_breaker_threshold: u32,
+    pub circuit_breaker_cooldown: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_args(args: &Args) -> Self {
+        Self {
+            initial_backoff: args.retry_initial_backoff(),
+            max_backoff: args.retry_max_backoff(),
+            circuit_breaker_threshold: args.circuit_breaker_threshold,
+            circuit_breaker_cooldown: args.circuit_breaker_cooldown(),
+        }
+    }
+
+    fn circuit_open(&self, consecutive_failures: u32) -> bool {
+        self.circuit_breaker_threshold > 0 && consecutive_failures >= self.circuit_breaker_threshold
+    }
+
+    /// How long to wait before the next run, given how many runs in a row
+    /// have failed
+    fn delay(&self, interval: Duration, consecutive_failures: u32) -> Duration {
+        if consecutive_failures == 0 {
+            interval
+        } else if self.circuit_open(consecutive_failures) {
+            jitter(self.circuit_breaker_cooldown.max(interval))
+        } else {
+            let mut backoff = self
+                .initial_backoff
+                .saturating_mul(2u32.saturating_pow(consecutive_failures - 1))
+                .min(self.max_backoff);
+            // a retry should never come later than the next regular run would
+            // (for tasks that run continuously, that'd be right away)
+            if !interval.is_zero() {
+                backoff = backoff.min(interval);
+            }
+            jitter(backoff)
+        }
+    }
+}
+
+/// Spread retries out over the back half of `delay`, so that tasks which
+/// failed together don't all retry together
+fn jitter(delay: Duration) -> Duration {
+    delay.mul_f64(rand::random_range(0.5..=1.0))
+}
+
 pub trait BackgroundTask {
     const LABEL: &'static str;
 
@@ -17,20 +73,84 @@ pub trait BackgroundTask {
         args: Args,
         mut shutdown_rx: broadcast::Receiver<()>,
     ) -> anyhow::Result<()> {
-        let interval = Self::interval(&args);
+        let policy = Self::retry_policy(&args);
+        let mut consecutive_failures = 0;
         loop {
             tracing::debug!(label = Self::LABEL, "invoking background task");
-            if let Err(error) = Self::run(state.as_ref(), &args).await {
-                tracing::error!(?error, "{}", Self::LABEL);
+            let started = Instant::now();
+            // some tasks run for a long time, and shouldn't hold up shutdown
+            let result = tokio::select! {
+                result = Self::run(state.as_ref(), &args) => result,
+                _ = shutdown_rx.recv() => return Ok(()),
+            };
+            let duration = started.elapsed();
+            let mut last_error = None;
+            match result {
+                Ok(()) => {
+                    if policy.circuit_open(consecutive_failures) {
+                        tracing::info!(label = Self::LABEL, "background task recovered");
+                    }
+                    consecutive_failures = 0;
+                }
+                Err(error) => {
+                    consecutive_failures += 1;
+                    tracing::error!(?error, consecutive_failures, "{}", Self::LABEL);
+                    last_error = Some(format!("{error:#}"));
+                    if consecutive_failures == policy.circuit_breaker_threshold {
+                        tracing::warn!(
+                            label = Self::LABEL,
+                            cooldown = ?policy.circuit_breaker_cooldown,
+                            "too many consecutive failures; backing off"
+                        );
+                    }
+                }
             }
+            let next_interval = Self::next_interval(state.as_ref(), &args).await;
+            let delay = policy.delay(next_interval, consecutive_failures);
+            let next_run = TimeDelta::from_std(delay)
+                .ok()
+                .and_then(|delay| Utc::now().checked_add_signed(delay));
+            let mut tasks_guard = state.tasks.write().await;
+            let status = tasks_guard.entry(Self::LABEL).or_default();
+            status.runs += 1;
+            status.consecutive_failures = consecutive_failures;
+            status.circuit_open = policy.circuit_open(consecutive_failures);
+            status.last_duration_secs = Some(duration.as_secs_f64());
+            status.next_run = next_run;
+            match last_error {
+                Some(error) => {
+                    status.failures += 1;
+                    status.last_error = Some(error);
+                }
+                None => status.last_success = Some(Utc::now()),
+            }
+            drop(tasks_guard);
             tokio::select! {
-                    _ = tokio::time::sleep(interval) => {},
+                    _ = tokio::time::sleep(delay) => {},
+                    _ = Self::requested(state.as_ref()) => {
+                        tracing::debug!(label = Self::LABEL, "background task requested early");
+                    },
                     _ = shutdown_rx.recv() => { return Ok(()) }
             }
         }
     }
 
     fn interval(args: &Args) -> Duration;
+
+    /// How long to wait after a successful run; tasks can override this to
+    /// adjust their schedule as they go
+    async fn next_interval(_state: &AppState, args: &Args) -> Duration {
+        Self::interval(args)
+    }
+
+    /// Resolves when something asks for this task to run ahead of schedule
+    async fn requested(_state: &AppState) {
+        std::future::pending().await
+    }
+
+    fn retry_policy(args: &Args) -> RetryPolicy {
+        RetryPolicy::from_args(args)
+    }
 }
 
 pub struct FetchInventory {}
@@ -39,11 +159,27 @@ impl BackgroundTask for FetchInventory {
     const LABEL: &'static str = "fetch inventory";
 
     async fn run(state: &AppState, args: &Args) -> anyhow::Result<()> {
-        let new_inventory =
-            envoy_api::fetch_inventory(&args.envoy_url, &args.envoy_jwt, &state.client).await?;
+        // device info is nice to have, so it shouldn't hold up the inventory
+        match state.fetch_device_info(&args.envoy_url).await {
+            Ok(new_info) => state.update_device_info(new_info).await,
+            Err(error) => {
+                tracing::warn!(?error, "failed to fetch envoy info");
+                state.device_info_failed();
+            }
+        }
+
+        let new_inventory = state
+            .with_envoy_auth(&args.envoy_url, |dialect, auth| async move {
+                state
+                    .track(
+                        dialect.inventory_endpoint(),
+                        dialect.fetch_inventory(&args.envoy_url, &auth, &state.client),
+                    )
+                    .await
+            })
+            .await?;
 
-        let mut guard = state.inventory.write().await;
-        *guard = new_inventory;
+        state.update_inventory(new_inventory).await;
 
         Ok(())
     }
@@ -59,16 +195,195 @@ impl BackgroundTask for FetchState {
     const LABEL: &'static str = "fetch state";
 
     async fn run(state: &AppState, args: &Args) -> anyhow::Result<()> {
-        let new_state =
-            envoy_api::fetch_state(&args.envoy_url, &args.envoy_jwt, &state.client).await?;
+        state.refresh.begin();
+        let result = Self::fetch(state, args).await;
+        state.refresh.finish(&result);
+        result
+    }
 
-        state.update_state(new_state).await;
+    fn interval(args: &Args) -> Duration {
+        args.poll_interval()
+    }
+
+    async fn next_interval(state: &AppState, args: &Args) -> Duration {
+        if !args.adaptive_polling {
+            return args.poll_interval();
+        }
+        // nothing much happens overnight
+        if let (Some(latitude), Some(longitude)) = (args.latitude, args.longitude)
+            && !solar::is_daylight(Utc::now(), latitude, longitude)
+        {
+            return args.max_poll_interval();
+        }
+        // the faster things are changing, the closer we get to the shortest
+        // interval
+        let change = state.recent_change_mw().await as f64;
+        let urgency = (change / args.poll_change_threshold_mw.max(1) as f64).min(1.0);
+        let interval = args.poll_interval().max(args.min_poll_interval());
+        let interval = interval - (interval - args.min_poll_interval()).mul_f64(urgency);
+        interval.min(args.max_poll_interval())
+    }
+
+    async fn requested(state: &AppState) {
+        state.refresh.requested().await
+    }
+}
+
+impl FetchState {
+    async fn fetch(state: &AppState, args: &Args) -> anyhow::Result<()> {
+        let previous = state.system_state.read().await.clone();
+        let fetch = state
+            .with_envoy_auth(&args.envoy_url, |dialect, auth| {
+                let previous = &previous;
+                async move {
+                    let fetch = dialect
+                        .fetch_state(&args.envoy_url, &auth, &state.client, previous)
+                        .await;
+                    state.record_endpoints(&fetch.endpoints).await;
+                    fetch.into_result()
+                }
+            })
+            .await?;
+
+        state
+            .update_state(fetch.state, fetch.counters_fetched)
+            .await;
+
+        Ok(())
+    }
+}
+
+/// Reads live power flows every second or so for one keepalive period, after
+/// which the stream is re-enabled by the next run
+pub struct StreamLiveData {}
+
+impl BackgroundTask for StreamLiveData {
+    const LABEL: &'static str = "stream live data";
+
+    async fn run(state: &AppState, args: &Args) -> anyhow::Result<()> {
+        let result = Self::stream(state, args).await;
+        if result.is_err() && state.stop_streaming() {
+            tracing::warn!("live stream failed; falling back to polling");
+        }
+        result
+    }
+
+    fn interval(_args: &Args) -> Duration {
+        Duration::ZERO
+    }
+}
+
+impl StreamLiveData {
+    async fn stream(state: &AppState, args: &Args) -> anyhow::Result<()> {
+        state
+            .with_envoy_auth(&args.envoy_url, |dialect, auth| async move {
+                if dialect != Dialect::Modern {
+                    anyhow::bail!("the live stream needs firmware 7.x or later");
+                }
+                let enable = || {
+                    state.track(
+                        "/ivp/livedata/stream",
+                        envoy_api::enable_live_stream(&args.envoy_url, &auth, &state.client),
+                    )
+                };
+                enable().await?;
+                let session_end = Instant::now() + args.stream_keepalive();
+                let mut ticker = tokio::time::interval(args.stream_read_interval());
+                ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                while Instant::now() < session_end {
+                    ticker.tick().await;
+                    let resp = state
+                        .track(
+                            "/ivp/livedata/status",
+                            envoy_api::fetch_live_status(&args.envoy_url, &auth, &state.client),
+                        )
+                        .await?;
+                    if !resp.is_streaming() {
+                        tracing::debug!("live stream stopped early; re-enabling it");
+                        enable().await?;
+                    }
+                    if !state.streaming.swap(true, Ordering::Relaxed) {
+                        tracing::info!("receiving live stream");
+                    }
+                    state.apply_live_reading(resp.reading()).await;
+                }
+                Ok(())
+            })
+            .await
+    }
+}
+
+pub struct FetchInverters {}
+
+impl BackgroundTask for FetchInverters {
+    const LABEL: &'static str = "fetch inverters";
+
+    async fn run(state: &AppState, args: &Args) -> anyhow::Result<()> {
+        let new_inverters = state
+            .with_envoy_auth(&args.envoy_url, |_dialect, auth| async move {
+                state
+                    .track(
+                        "/api/v1/production/inverters",
+                        envoy_api::fetch_inverters(&args.envoy_url, &auth, &state.client),
+                    )
+                    .await
+            })
+            .await?;
+
+        state.update_inverters(new_inverters).await;
 
         Ok(())
     }
 
     fn interval(args: &Args) -> Duration {
-        args.poll_interval()
+        args.inverter_poll_interval()
+    }
+}
+
+pub struct FetchBatteries {}
+
+impl BackgroundTask for FetchBatteries {
+    const LABEL: &'static str = "fetch batteries";
+
+    async fn run(state: &AppState, args: &Args) -> anyhow::Result<()> {
+        // the inventory poll finds the batteries; this only keeps their state
+        // of charge fresh in between
+        if !state.has_batteries().await {
+            return Ok(());
+        }
+
+        let new_inventory = state
+            .with_envoy_auth(&args.envoy_url, |dialect, auth| async move {
+                state
+                    .track(
+                        dialect.inventory_endpoint(),
+                        dialect.fetch_inventory(&args.envoy_url, &auth, &state.client),
+                    )
+                    .await
+            })
+            .await?;
+
+        state.update_batteries(new_inventory).await;
+
+        Ok(())
+    }
+
+    fn interval(args: &Args) -> Duration {
+        args.battery_poll_interval()
+    }
+}
+
+pub struct RefreshToken {}
+
+impl BackgroundTask for RefreshToken {
+    const LABEL: &'static str = "refresh token";
+
+    async fn run(state: &AppState, _args: &Args) -> anyhow::Result<()> {
+        state.tokens.refresh_if_expiring().await
+    }
+
+    fn interval(_args: &Args) -> Duration {
+        Duration::from_secs(3600)
     }
 }
 
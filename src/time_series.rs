@@ -1,8 +1,10 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
-use chrono::{DateTime, Datelike, TimeDelta, Timelike, Utc};
+use chrono::{DateTime, Datelike, Local, NaiveDate, TimeDelta, Timelike, Utc};
 use itertools::Itertools;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+
+use crate::args::Args;
 
 type Point = i64;
 
@@ -23,47 +25,593 @@ fn truncate_to_week<H: chrono::TimeZone>(dt: &DateTime<H>) -> DateTime<H> {
     truncate_to_day(&start_of_week)
 }
 
+fn truncate_to_minutes<H: chrono::TimeZone>(dt: &DateTime<H>, minutes: u32) -> DateTime<H> {
+    let time = dt.time();
+    let minute = time.minute() - time.minute() % minutes;
+    dt.with_time(chrono::NaiveTime::from_hms_opt(time.hour(), minute, 0).unwrap())
+        .unwrap()
+}
+
+fn truncate_to_month<H: chrono::TimeZone>(dt: &DateTime<H>) -> DateTime<H> {
+    truncate_to_day(&dt.with_day(1).unwrap())
+}
+
+/// How finely to bucket history when querying it
+#[derive(clap::ValueEnum, Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Step {
+    Raw,
+    #[serde(rename = "5m")]
+    #[value(name = "5m")]
+    FiveMinutes,
+    #[serde(rename = "15m")]
+    #[value(name = "15m")]
+    FifteenMinutes,
+    #[default]
+    Hour,
+    Day,
+    Week,
+    Month,
+}
+
+impl Step {
+    pub fn truncate(&self, dt: &DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Self::Raw => *dt,
+            Self::FiveMinutes => truncate_to_minutes(dt, 5),
+            Self::FifteenMinutes => truncate_to_minutes(dt, 15),
+            Self::Hour => truncate_to_hour(dt),
+            Self::Day => truncate_to_day(dt),
+            Self::Week => truncate_to_week(dt),
+            Self::Month => truncate_to_month(dt),
+        }
+    }
+
+    /// Where the bucket starting at `start` ends
+    pub fn end(&self, start: &DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Self::Raw => *start,
+            Self::FiveMinutes => *start + TimeDelta::minutes(5),
+            Self::FifteenMinutes => *start + TimeDelta::minutes(15),
+            Self::Hour => *start + TimeDelta::hours(1),
+            Self::Day => *start + TimeDelta::days(1),
+            Self::Week => *start + TimeDelta::days(7),
+            Self::Month => start.checked_add_months(chrono::Months::new(1)).unwrap(),
+        }
+    }
+
+    /// Like [`Step::truncate`], but with days, weeks, and months starting at
+    /// local midnight rather than UTC midnight
+    pub fn truncate_local(&self, dt: &DateTime<Utc>) -> DateTime<Utc> {
+        if !matches!(self, Self::Day | Self::Week | Self::Month) {
+            return self.truncate(dt);
+        }
+        let local = self
+            .truncate(&dt.with_timezone(&Local).naive_local().and_utc())
+            .naive_utc();
+        // a midnight skipped for daylight saving time starts at the hour after
+        local
+            .and_local_timezone(Local)
+            .earliest()
+            .or_else(|| {
+                (local + TimeDelta::hours(1))
+                    .and_local_timezone(Local)
+                    .earliest()
+            })
+            .map_or_else(|| self.truncate(dt), |start| start.with_timezone(&Utc))
+    }
+
+    /// Where the bucket starting at `start` (from [`Step::truncate_local`])
+    /// ends
+    pub fn end_local(&self, start: &DateTime<Utc>) -> DateTime<Utc> {
+        if !matches!(self, Self::Day | Self::Week | Self::Month) {
+            return self.end(start);
+        }
+        // local days can be an hour longer or shorter than UTC ones
+        self.truncate_local(&(self.end(start) + TimeDelta::hours(2)))
+    }
+}
+
+/// How to summarize the points within each bucket
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    #[default]
+    Avg,
+    Min,
+    Max,
+    Sum,
+    /// Power integrated over time, so milliwatts become milliwatt-hours
+    Energy,
+    /// Like `Energy`, but only counting positive readings
+    #[serde(rename = "energy_in")]
+    EnergyIn,
+    /// Like `Energy`, but only counting negative readings (as a positive
+    /// number)
+    #[serde(rename = "energy_out")]
+    EnergyOut,
+}
+
+impl Aggregation {
+    /// `None` if the bucket doesn't say, like which way energy flowed in
+    /// rollups from older versions, or the extremes of older rollups that
+    /// readings only held into
+    fn apply(&self, stats: &Statistics) -> Option<Point> {
+        match self {
+            Self::Avg => Some(stats.average),
+            Self::Min => stats.min,
+            Self::Max => stats.max,
+            Self::Sum => Some(stats.sum),
+            // the integrals are in milliwatt-seconds
+            Self::Energy => Some(stats.integral / 3600),
+            Self::EnergyIn => Some(stats.energy()?.energy_in_mwh),
+            Self::EnergyOut => Some(stats.energy()?.energy_out_mwh),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct HistoryPoint {
+    pub timestamp: DateTime<Utc>,
+    pub value: Point,
+    /// How much of the bucket (so far) is covered by readings, from 0 to 1;
+    /// left out for raw readings
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coverage: Option<f64>,
+}
+
+/// History read back for a query
+#[derive(Debug)]
+pub enum History {
+    Raw(BTreeMap<DateTime<Utc>, Point>),
+    /// Rollups, for ranges that go back further than the raw data is kept
+    Rollup(Rollup, BTreeMap<DateTime<Utc>, Statistics>),
+}
+
+impl History {
+    /// How many readings or rollup buckets there are
+    pub fn size(&self) -> usize {
+        match self {
+            Self::Raw(points) => points.len(),
+            Self::Rollup(_, buckets) => buckets.len(),
+        }
+    }
+}
+
+/// Group history into buckets of `step` and summarize each one
+pub fn bucketize(
+    history: &History,
+    step: Step,
+    aggregation: Aggregation,
+    max_gap: TimeDelta,
+) -> Vec<HistoryPoint> {
+    match history {
+        History::Raw(points) => bucketize_raw(points, step, aggregation, max_gap),
+        History::Rollup(rollup, buckets) => bucketize_rollups(*rollup, buckets, step, aggregation),
+    }
+}
+
+/// Group raw points into buckets of `step`, weighting each reading by how
+/// long it held for the same way as the rollups do
+fn bucketize_raw(
+    points: &BTreeMap<DateTime<Utc>, Point>,
+    step: Step,
+    aggregation: Aggregation,
+    max_gap: TimeDelta,
+) -> Vec<HistoryPoint> {
+    if step == Step::Raw {
+        return points
+            .iter()
+            .map(|(timestamp, value)| HistoryPoint {
+                timestamp: *timestamp,
+                value: *value,
+                coverage: None,
+            })
+            .collect();
+    }
+    // every bucket that any reading holds into
+    let mut buckets = BTreeSet::new();
+    let mut readings = points.keys().peekable();
+    while let Some(dt) = readings.next() {
+        let held_until = readings
+            .peek()
+            .map_or(*dt, |next| (**next).min(*dt + max_gap));
+        let mut bucket = step.truncate(dt);
+        loop {
+            buckets.insert(bucket);
+            bucket = step.end(&bucket);
+            if bucket >= held_until {
+                break;
+            }
+        }
+    }
+    buckets
+        .into_iter()
+        .filter_map(|bucket| {
+            let stats = Statistics::from_points(points, bucket, step.end(&bucket), max_gap);
+            if stats.is_empty() {
+                return None;
+            }
+            Some(HistoryPoint {
+                timestamp: bucket,
+                value: aggregation.apply(&stats)?,
+                coverage: Some(stats.coverage),
+            })
+        })
+        .collect()
+}
+
+/// Combine rollup buckets into buckets of `step`, or leave them as they are if
+/// `step` is finer than they are
+fn bucketize_rollups(
+    rollup: Rollup,
+    buckets: &BTreeMap<DateTime<Utc>, Statistics>,
+    step: Step,
+    aggregation: Aggregation,
+) -> Vec<HistoryPoint> {
+    let now = Utc::now();
+    let mut grouped: BTreeMap<DateTime<Utc>, Vec<&Statistics>> = BTreeMap::new();
+    for (start, stats) in buckets {
+        grouped.entry(step.truncate(start)).or_default().push(stats);
+    }
+    grouped
+        .into_iter()
+        .filter_map(|(bucket, parts)| {
+            let end = step.end(&bucket).max(rollup.end(&bucket)).min(now);
+            let stats = Statistics::combine(parts, (end - bucket).num_seconds());
+            // leave out buckets that can't answer, rather than guessing
+            Some(HistoryPoint {
+                timestamp: bucket,
+                value: aggregation.apply(&stats)?,
+                coverage: Some(stats.coverage),
+            })
+        })
+        .collect()
+}
+
+/// Resolutions that history is rolled up to and persisted at, each one built
+/// from the one before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Rollup {
+    FiveMinutes,
+    Hour,
+    Day,
+    Month,
+}
+
+impl Rollup {
+    pub const ALL: [Self; 4] = [Self::FiveMinutes, Self::Hour, Self::Day, Self::Month];
+
+    pub fn table(&self) -> &'static str {
+        match self {
+            Self::FiveMinutes => "history_5m",
+            Self::Hour => "history_hourly",
+            Self::Day => "history_daily",
+            Self::Month => "history_monthly",
+        }
+    }
+
+    pub fn truncate(&self, dt: &DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Self::FiveMinutes => truncate_to_minutes(dt, 5),
+            Self::Hour => truncate_to_hour(dt),
+            Self::Day => truncate_to_day(dt),
+            Self::Month => truncate_to_month(dt),
+        }
+    }
+
+    /// Where the bucket starting at `start` ends
+    pub fn end(&self, start: &DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Self::FiveMinutes => *start + TimeDelta::minutes(5),
+            Self::Hour => *start + TimeDelta::hours(1),
+            Self::Day => *start + TimeDelta::days(1),
+            Self::Month => start.checked_add_months(chrono::Months::new(1)).unwrap(),
+        }
+    }
+
+    /// The resolution this one is built from, if it isn't built from raw data
+    fn finer(&self) -> Option<Self> {
+        match self {
+            Self::FiveMinutes => None,
+            Self::Hour => Some(Self::FiveMinutes),
+            Self::Day => Some(Self::Hour),
+            Self::Month => Some(Self::Day),
+        }
+    }
+}
+
+/// How long to keep history at each resolution; `None` keeps it forever
+#[derive(Debug, Clone)]
+pub struct Retention {
+    pub raw: Option<TimeDelta>,
+    pub five_minutes: Option<TimeDelta>,
+    pub hour: Option<TimeDelta>,
+    pub day: Option<TimeDelta>,
+    pub month: Option<TimeDelta>,
+}
+
+impl Retention {
+    pub fn from_args(args: &Args) -> Self {
+        Self {
+            raw: args.raw_retention(),
+            five_minutes: args.five_minute_retention(),
+            hour: args.hourly_retention(),
+            day: args.daily_retention(),
+            month: args.monthly_retention(),
+        }
+    }
+
+    pub fn rollup(&self, rollup: Rollup) -> Option<TimeDelta> {
+        match rollup {
+            Rollup::FiveMinutes => self.five_minutes,
+            Rollup::Hour => self.hour,
+            Rollup::Day => self.day,
+            Rollup::Month => self.month,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Clone)]
-struct Statistics {
-    average: Point,
-    count: usize,
-    max: Option<Point>,
-    min: Option<Point>,
+pub struct Statistics {
+    /// Weighted by how long each reading held for
+    pub average: Point,
+    pub count: usize,
+    pub max: Option<Point>,
+    pub min: Option<Point>,
+    /// How much of the bucket (so far) is covered by readings, from 0 to 1
+    pub coverage: f64,
+    /// Only filled in for series of power readings
+    #[serde(flatten)]
+    pub energy: Option<Energy>,
+    #[serde(skip)]
+    pub sum: Point,
+    /// Each reading multiplied by the number of seconds it held for
+    #[serde(skip)]
+    pub integral: i64,
+    /// The same, but only for positive readings; unknown for rollups written
+    /// before energy was split by direction
+    #[serde(skip)]
+    pub positive_integral: Option<i64>,
+    #[serde(skip)]
+    pub covered_secs: i64,
+}
+
+/// Power integrated over a bucket, with what flowed each way kept apart
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct Energy {
+    /// From positive readings, like importing from the grid
+    pub energy_in_mwh: Point,
+    /// From negative readings (as a positive number), like exporting to the
+    /// grid
+    pub energy_out_mwh: Point,
+}
+
+/// How many seconds of `[start, end)` have gone by, as far as `raw` knows
+fn span_secs(
+    raw: &BTreeMap<DateTime<Utc>, Point>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> i64 {
+    let until = raw
+        .last_key_value()
+        .map_or(end, |(dt, _)| (*dt).clamp(start, end));
+    (until - start).num_seconds()
 }
 
 impl Statistics {
-    fn from_points<'a>(values: impl IntoIterator<Item = &'a Point>) -> Self {
-        let (sum, max, min, count) =
-            values
-                .into_iter()
-                .fold((0, None, None, 0usize), |(sum, max, min, count), value| {
-                    let new_max = match (max, value) {
-                        (None, v) => Some(v),
-                        (Some(v1), v2) if v1 < v2 => Some(v2),
-                        (o, _) => o,
-                    };
-                    let new_min = match (min, value) {
-                        (None, v) => Some(v),
-                        (Some(v1), v2) if v1 > v2 => Some(v2),
-                        (o, _) => o,
-                    };
-                    (sum + value, new_max, new_min, count + 1)
-                });
+    /// Statistics over the readings in `[start, end)`. Each reading holds
+    /// until the next one (including a reading from before `start`, which
+    /// holds into the bucket), but for no longer than `max_gap`, after which
+    /// the data counts as missing.
+    fn from_points(
+        raw: &BTreeMap<DateTime<Utc>, Point>,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        max_gap: TimeDelta,
+    ) -> Self {
+        let (mut sum, mut count, mut min, mut max) = (0, 0, None::<Point>, None::<Point>);
+        for value in raw.range(start..end).map(|(_, value)| *value) {
+            sum += value;
+            count += 1;
+            min = Some(min.map_or(value, |min| min.min(value)));
+            max = Some(max.map_or(value, |max| max.max(value)));
+        }
+
+        let (mut integral, mut positive_integral, mut covered_secs) = (0, 0, 0);
+        let first = raw.range(..start).next_back().map_or(start, |(dt, _)| *dt);
+        let mut points = raw.range(first..).peekable();
+        while let Some((dt, value)) = points.next() {
+            if *dt >= end {
+                break;
+            }
+            let Some((next, _)) = points.peek() else {
+                break;
+            };
+            let held_from = (*dt).max(start);
+            let held_until = (**next).min(*dt + max_gap).min(end);
+            let secs = (held_until - held_from).num_seconds();
+            if secs > 0 {
+                integral += value * secs;
+                positive_integral += (*value).max(0) * secs;
+                covered_secs += secs;
+                // a bucket that a reading only holds into was at that
+                // reading the whole time
+                if count == 0 {
+                    min = Some(*value);
+                    max = Some(*value);
+                }
+            }
+        }
+
+        Self::from_parts(
+            sum,
+            count,
+            min,
+            max,
+            integral,
+            Some(positive_integral),
+            covered_secs,
+            span_secs(raw, start, end),
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_parts(
+        sum: Point,
+        count: usize,
+        min: Option<Point>,
+        max: Option<Point>,
+        integral: i64,
+        positive_integral: Option<i64>,
+        covered_secs: i64,
+        span_secs: i64,
+    ) -> Self {
+        // a lone reading hasn't held for any time yet
+        let average = if covered_secs > 0 {
+            integral / covered_secs
+        } else {
+            sum / (count.max(1) as i64)
+        };
+        let coverage = if span_secs > 0 {
+            (covered_secs as f64 / span_secs as f64).min(1.0)
+        } else {
+            1.0
+        };
         Self {
-            average: sum / (count as i64),
+            average,
             count,
-            max: max.copied(),
-            min: min.copied(),
+            max,
+            min,
+            coverage,
+            energy: None,
+            sum,
+            integral,
+            positive_integral,
+            covered_secs,
+        }
+    }
+
+    /// The energy in the integrals, for series where they're power, if we
+    /// know which way it flowed
+    fn energy(&self) -> Option<Energy> {
+        // the integrals are in milliwatt-seconds
+        let positive_integral = self.positive_integral?;
+        Some(Energy {
+            energy_in_mwh: positive_integral / 3600,
+            energy_out_mwh: (positive_integral - self.integral) / 3600,
+        })
+    }
+
+    /// Fill in `energy` from the integrals
+    fn with_energy(mut self) -> Self {
+        self.energy = self.energy();
+        self
+    }
+
+    /// Statistics over every reading that went into each of `parts`, which
+    /// together make up a bucket of which `span_secs` have gone by
+    fn combine<'a>(parts: impl IntoIterator<Item = &'a Statistics>, span_secs: i64) -> Self {
+        let mut combined = Self::from_parts(0, 0, None, None, 0, Some(0), 0, span_secs);
+        for part in parts {
+            combined.sum += part.sum;
+            combined.count += part.count;
+            combined.min = combined.min.into_iter().chain(part.min).min();
+            combined.max = combined.max.max(part.max);
+            combined.integral += part.integral;
+            combined.positive_integral = combined
+                .positive_integral
+                .zip(part.positive_integral)
+                .map(|(combined, part)| combined + part);
+            combined.covered_secs += part.covered_secs;
         }
+        Self::from_parts(
+            combined.sum,
+            combined.count,
+            combined.min,
+            combined.max,
+            combined.integral,
+            combined.positive_integral,
+            combined.covered_secs,
+            span_secs,
+        )
+    }
+
+    fn is_empty(&self) -> bool {
+        self.count == 0 && self.covered_secs == 0
+    }
+}
+
+/// How many hours either side of midnight the Envoy can reset its daily
+/// counters; its midnight needn't be exactly ours
+const RESET_WINDOW_HOURS: u32 = 3;
+
+/// A daily counter reading no more than this (100 Wh) is as good as reset
+const RESET_NEAR_ZERO_MWH: Point = 100_000;
+
+/// If `dt` is close enough to midnight for the Envoy to reset its daily
+/// counters, the day that midnight ends
+fn day_ending_near(dt: DateTime<Utc>) -> Option<NaiveDate> {
+    let local = dt.with_timezone(&Local);
+    match local.hour() {
+        hour if hour >= 24 - RESET_WINDOW_HOURS => Some(local.date_naive()),
+        hour if hour < RESET_WINDOW_HOURS => local.date_naive().pred_opt(),
+        _ => None,
     }
 }
 
+/// Whether a daily counter going from `before` to `after` was reset, rather
+/// than dipping for a moment or switching between meters
+fn is_reset(before: Point, after: Point) -> bool {
+    after < before && after <= (before / 10).max(RESET_NEAR_ZERO_MWH)
+}
+
+/// A rollup bucket that won't see any more data, and so can be persisted
+pub type ClosedBucket = (Rollup, DateTime<Utc>, Statistics);
+
 #[derive(Debug, Default)]
 pub struct TimeSeriesRow {
     raw_data: BTreeMap<DateTime<Utc>, Point>,
+    five_minute_data: BTreeMap<DateTime<Utc>, Statistics>,
     hourly_data: BTreeMap<DateTime<Utc>, Statistics>,
     daily_data: BTreeMap<DateTime<Utc>, Statistics>,
+    monthly_data: BTreeMap<DateTime<Utc>, Statistics>,
+    /// Built from the daily rollups rather than persisted
     weekly_data: BTreeMap<DateTime<Utc>, Statistics>,
+    /// The last reading of each day, for counters that reset daily
+    daily_totals: BTreeMap<NaiveDate, Point>,
+}
+
+/// Summary of a counter that resets every day, like the energy produced today
+#[derive(Debug, Serialize)]
+pub struct DailyTotalSummary {
+    today: Option<Point>,
+    /// The same total worked out from power readings since the reset, as a
+    /// check on the Envoy's own counter
+    #[serde(skip_serializing_if = "Option::is_none")]
+    integrated_today: Option<Point>,
+    /// `integrated_today` less `today`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    integrated_delta: Option<Point>,
+    /// The final total for each of the past week's days
+    daily_totals: BTreeMap<NaiveDate, Point>,
+}
+
+impl DailyTotalSummary {
+    pub fn today(&self) -> Option<Point> {
+        self.today
+    }
+
+    pub fn integrated_delta(&self) -> Option<Point> {
+        self.integrated_delta
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum SeriesSummary {
+    Statistics(Box<TimeSeriesSummary>),
+    DailyTotal(DailyTotalSummary),
 }
 
 #[derive(Debug, Serialize)]
@@ -71,13 +619,44 @@ pub struct TimeSeriesSummary {
     hour: Option<Statistics>,
     day: Option<Statistics>,
     week: Option<Statistics>,
-    last_24h: BTreeMap<chrono::DateTime<Utc>, Point>,
+    /// Hourly statistics, so that each hour's average can be weighed by how
+    /// much of it readings cover
+    last_24h: BTreeMap<chrono::DateTime<Utc>, Statistics>,
 }
 
 impl TimeSeriesRow {
-    pub fn append<H: chrono::TimeZone>(&mut self, dt: DateTime<H>, datum: Point) {
-        self.append_raw(dt, datum);
-        self.aggregate();
+    /// Add a reading, returning any rollup buckets that it closed
+    pub fn append<H: chrono::TimeZone>(
+        &mut self,
+        dt: DateTime<H>,
+        datum: Point,
+        max_gap: TimeDelta,
+    ) -> Vec<ClosedBucket> {
+        let utc = dt.with_timezone(&Utc);
+        let previous = self.raw_data.keys().next_back().copied();
+        self.append_raw(utc, datum);
+        let Some(previous) = previous.filter(|previous| *previous < utc) else {
+            self.aggregate(&utc, max_gap);
+            return Vec::new();
+        };
+        // the previous reading now holds until this one, possibly through
+        // buckets which have no readings of their own
+        let mut bucket = Rollup::FiveMinutes.truncate(&previous);
+        let last = Rollup::FiveMinutes.truncate(&utc.min(previous + max_gap));
+        while bucket <= last {
+            self.aggregate(&bucket, max_gap);
+            bucket += TimeDelta::minutes(5);
+        }
+        self.aggregate(&utc, max_gap);
+        // a reading in a new bucket means that the ones before it are done
+        Rollup::ALL
+            .into_iter()
+            .flat_map(|rollup| {
+                self.rollup_data(rollup)
+                    .range(rollup.truncate(&previous)..rollup.truncate(&utc))
+                    .map(move |(bucket, stats)| (rollup, *bucket, stats.clone()))
+            })
+            .collect()
     }
 
     pub fn append_raw<H: chrono::TimeZone>(&mut self, dt: DateTime<H>, datum: Point) {
@@ -85,23 +664,168 @@ impl TimeSeriesRow {
         self.raw_data.insert(utc, datum);
     }
 
-    pub fn summary(&self) -> TimeSeriesSummary {
+    pub fn append_daily_total(&mut self, date: NaiveDate, total: Point) {
+        self.daily_totals.insert(date, total);
+    }
+
+    /// For counters that reset daily: a reading at `dt` that falls to near
+    /// zero around midnight means the Envoy reset the counter, so the reading
+    /// before it was its day's total. A counter that never went up all day
+    /// (like production on a day of heavy snow) has nothing to fall from, so
+    /// the first reading after our own midnight closes its day instead.
+    /// Returns the day and its total, if either closed it.
+    pub fn close_day(&mut self, dt: DateTime<Utc>, value: Point) -> Option<(NaiveDate, Point)> {
+        let date = day_ending_near(dt)?;
+        if self.daily_totals.contains_key(&date) {
+            return None;
+        }
+        let (latest, total) = self.latest()?;
+        let local_date = |dt: DateTime<Utc>| dt.with_timezone(&Local).date_naive();
+        let never_rose = total <= RESET_NEAR_ZERO_MWH
+            && value <= RESET_NEAR_ZERO_MWH
+            && local_date(latest) <= date
+            && local_date(dt) > date;
+        if !is_reset(total, value) && !never_rose {
+            return None;
+        }
+        self.append_daily_total(date, total);
+        Some((date, total))
+    }
+
+    pub fn append_rollup(&mut self, rollup: Rollup, dt: DateTime<Utc>, stats: Statistics) {
+        self.rollup_data_mut(rollup).insert(dt, stats);
+    }
+
+    fn rollup_data(&self, rollup: Rollup) -> &BTreeMap<DateTime<Utc>, Statistics> {
+        match rollup {
+            Rollup::FiveMinutes => &self.five_minute_data,
+            Rollup::Hour => &self.hourly_data,
+            Rollup::Day => &self.daily_data,
+            Rollup::Month => &self.monthly_data,
+        }
+    }
+
+    fn rollup_data_mut(&mut self, rollup: Rollup) -> &mut BTreeMap<DateTime<Utc>, Statistics> {
+        match rollup {
+            Rollup::FiveMinutes => &mut self.five_minute_data,
+            Rollup::Hour => &mut self.hourly_data,
+            Rollup::Day => &mut self.daily_data,
+            Rollup::Month => &mut self.monthly_data,
+        }
+    }
+
+    /// Whether we still have raw data in memory going back as far as `from`
+    pub fn covers(&self, from: DateTime<Utc>) -> bool {
+        self.raw_data
+            .keys()
+            .next()
+            .is_some_and(|earliest| *earliest <= from)
+    }
+
+    /// The rollup buckets that start between `from` and `to`, including the
+    /// one that `from` falls into
+    pub fn rollups(
+        &self,
+        rollup: Rollup,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> BTreeMap<DateTime<Utc>, Statistics> {
+        self.rollup_data(rollup)
+            .range(rollup.truncate(&from)..=to)
+            .map(|(dt, stats)| (*dt, stats.clone()))
+            .collect()
+    }
+
+    pub fn points(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> BTreeMap<DateTime<Utc>, Point> {
+        self.raw_data
+            .range(from..=to)
+            .map(|(dt, value)| (*dt, *value))
+            .collect()
+    }
+
+    /// How much the most recent reading differs from the one before it
+    pub fn last_change(&self) -> Option<Point> {
+        let mut latest = self.raw_data.values().rev();
+        let (current, previous) = (latest.next()?, latest.next()?);
+        Some(current - previous)
+    }
+
+    pub fn latest(&self) -> Option<(DateTime<Utc>, Point)> {
+        self.raw_data
+            .last_key_value()
+            .map(|(dt, value)| (*dt, *value))
+    }
+
+    /// When this counter was last reset (see `close_day`), as far back as the
+    /// readings in memory go, if that was within the last day
+    pub fn last_reset(&self) -> Option<DateTime<Utc>> {
+        let (reset, _) = self
+            .raw_data
+            .iter()
+            .rev()
+            .tuple_windows()
+            .find(|((at, later), (_, earlier))| {
+                is_reset(**earlier, **later) && day_ending_near(**at).is_some()
+            })?
+            .0;
+        Some(*reset).filter(|reset| Utc::now() - *reset < TimeDelta::days(1))
+    }
+
+    /// `day_start` is when the counter was last reset, and `integrated_today`
+    /// is the total since then worked out from some other series; see
+    /// `energy_since`
+    pub fn daily_total_summary(
+        &self,
+        day_start: Option<DateTime<Utc>>,
+        integrated_today: Option<Point>,
+    ) -> DailyTotalSummary {
+        let date = Local::now().date_naive();
+        let today = self
+            .latest()
+            .filter(|(dt, _)| day_start.is_none_or(|start| *dt >= start))
+            .map(|(_, value)| value);
+        DailyTotalSummary {
+            integrated_today,
+            today,
+            integrated_delta: integrated_today
+                .zip(today)
+                .map(|(integrated, today)| integrated - today),
+            daily_totals: self
+                .daily_totals
+                .range(date - TimeDelta::days(7)..)
+                .map(|(date, total)| (*date, *total))
+                .collect(),
+        }
+    }
+
+    /// Power integrated from `start` until now, if there are readings that far
+    /// back
+    pub fn energy_since(&self, start: DateTime<Utc>, max_gap: TimeDelta) -> Option<Energy> {
+        if !self.covers(start) {
+            return None;
+        }
+        Statistics::from_points(&self.raw_data, start, Utc::now(), max_gap)
+            .with_energy()
+            .energy
+    }
+
+    /// `energy` says whether this series is power, which can be integrated
+    /// into energy
+    pub fn summary(&self, energy: bool) -> TimeSeriesSummary {
         let now = Utc::now();
-        let hour = self.hourly_data.get(&truncate_to_hour(&now)).cloned();
-        let day = self.daily_data.get(&truncate_to_day(&now)).cloned();
-        let week = self.weekly_data.get(&truncate_to_week(&now)).cloned();
+        let with_energy = |stats: Statistics| if energy { stats.with_energy() } else { stats };
+        let bucket = |data: &BTreeMap<DateTime<Utc>, Statistics>, at| {
+            data.get(&at).cloned().map(with_energy)
+        };
+        let hour = bucket(&self.hourly_data, truncate_to_hour(&now));
+        let day = bucket(&self.daily_data, truncate_to_day(&now));
+        let week = bucket(&self.weekly_data, truncate_to_week(&now));
 
         let yesterday = now - TimeDelta::days(1);
         let last_24h = self
             .hourly_data
-            .iter()
-            .filter_map(|(d, s)| {
-                if d >= &yesterday {
-                    Some((*d, s.average))
-                } else {
-                    None
-                }
-            })
+            .range(yesterday..)
+            .map(|(d, s)| (*d, with_energy(s.clone())))
             .collect();
 
         TimeSeriesSummary {
@@ -112,74 +836,335 @@ impl TimeSeriesRow {
         }
     }
 
-    pub fn maintain(&mut self) {
-        let threshold = Utc::now() - TimeDelta::days(14);
-        self.raw_data.retain(|d, _| d >= &threshold);
+    pub fn maintain(&mut self, retention: &Retention) {
+        let now = Utc::now();
+        if let Some(keep) = retention.raw {
+            let threshold = now - keep;
+            self.raw_data.retain(|d, _| d >= &threshold);
+        }
+        for rollup in Rollup::ALL {
+            if let Some(keep) = retention.rollup(rollup) {
+                let threshold = now - keep;
+                self.rollup_data_mut(rollup).retain(|d, _| d >= &threshold);
+            }
+        }
+        if let Some(keep) = retention.day {
+            let threshold = now - keep;
+            self.weekly_data.retain(|d, _| d >= &threshold);
+            self.daily_totals
+                .retain(|date, _| *date >= threshold.date_naive());
+        }
     }
 
-    fn aggregate_historical_generic<'a, D, F>(
-        data: D,
-        f: F,
-    ) -> impl Iterator<Item = (DateTime<Utc>, Statistics)> + use<'a, D, F>
-    where
-        F: Fn(&DateTime<Utc>) -> DateTime<Utc>,
-        D: Iterator<Item = (&'a DateTime<Utc>, &'a i64)>,
-    {
-        data.into_group_map_by(|r| f(r.0))
-            .into_iter()
-            .map(|(k, v)| (k, Statistics::from_points(v.into_iter().map(|v| v.1))))
-    }
+    /// Fill in any rollups that weren't loaded from the database (like the
+    /// ones still open when we last stopped) from the raw data, returning
+    /// those which have closed since
+    pub fn aggregate_historical(&mut self, max_gap: TimeDelta) -> Vec<ClosedBucket> {
+        let latest = self.raw_data.keys().next_back().copied();
+        let mut filled: Vec<(Rollup, DateTime<Utc>)> = Vec::new();
 
-    fn aggregate_generic<F>(&self, f: F) -> Option<(DateTime<Utc>, Statistics)>
-    where
-        F: Fn(&DateTime<Utc>) -> DateTime<Utc>,
-    {
-        let schwartz = self
-            .raw_data
-            .iter()
-            .map(|(d, p)| (f(d), d, p))
-            .collect::<Vec<_>>();
-        let current_truncated = schwartz.iter().map(|(h, _, _)| h).max().cloned()?;
-        let current_values: BTreeMap<chrono::DateTime<Utc>, Point> = schwartz
-            .iter()
-            .filter_map(|(h, d, p)| {
-                if *h == current_truncated {
-                    Some((**d, **p))
-                } else {
-                    None
+        // every five minutes that any reading holds into
+        let mut held = BTreeSet::new();
+        let mut points = self.raw_data.keys().peekable();
+        while let Some(dt) = points.next() {
+            let held_until = points
+                .peek()
+                .map_or(*dt, |next| (**next).min(*dt + max_gap));
+            let mut bucket = Rollup::FiveMinutes.truncate(dt);
+            loop {
+                held.insert(bucket);
+                bucket += TimeDelta::minutes(5);
+                if bucket >= held_until {
+                    break;
                 }
+            }
+        }
+        for bucket in held {
+            if self.five_minute_data.contains_key(&bucket) {
+                continue;
+            }
+            let end = Rollup::FiveMinutes.end(&bucket);
+            let stats = Statistics::from_points(&self.raw_data, bucket, end, max_gap);
+            if !stats.is_empty() {
+                filled.push((Rollup::FiveMinutes, bucket));
+                self.five_minute_data.insert(bucket, stats);
+            }
+        }
+
+        for rollup in Rollup::ALL {
+            let Some(finer) = rollup.finer() else {
+                continue;
+            };
+            let missing: BTreeSet<_> = self
+                .rollup_data(finer)
+                .keys()
+                .map(|dt| rollup.truncate(dt))
+                .filter(|bucket| !self.rollup_data(rollup).contains_key(bucket))
+                .collect();
+            for bucket in missing {
+                let end = rollup.end(&bucket);
+                let stats = Statistics::combine(
+                    self.rollup_data(finer).range(bucket..end).map(|(_, s)| s),
+                    span_secs(&self.raw_data, bucket, end),
+                );
+                filled.push((rollup, bucket));
+                self.rollup_data_mut(rollup).insert(bucket, stats);
+            }
+        }
+
+        let weeks: BTreeSet<_> = self.daily_data.keys().map(truncate_to_week).collect();
+        for week in weeks {
+            self.aggregate_week(&week);
+        }
+
+        filled
+            .into_iter()
+            .filter(|(rollup, bucket)| {
+                latest.is_none_or(|latest| rollup.truncate(&latest) != *bucket)
             })
-            .collect();
-        Some((
-            current_truncated,
-            Statistics::from_points(current_values.values()),
-        ))
+            .filter_map(|(rollup, bucket)| {
+                let stats = self.rollup_data(rollup).get(&bucket)?.clone();
+                Some((rollup, bucket, stats))
+            })
+            .collect()
+    }
+
+    /// Bring the buckets that `at` falls into up to date
+    fn aggregate(&mut self, at: &DateTime<Utc>, max_gap: TimeDelta) {
+        for rollup in Rollup::ALL {
+            let bucket = rollup.truncate(at);
+            let end = rollup.end(&bucket);
+            let stats = match rollup.finer() {
+                None => Statistics::from_points(&self.raw_data, bucket, end, max_gap),
+                Some(finer) => Statistics::combine(
+                    self.rollup_data(finer).range(bucket..end).map(|(_, s)| s),
+                    span_secs(&self.raw_data, bucket, end),
+                ),
+            };
+            if !stats.is_empty() {
+                self.rollup_data_mut(rollup).insert(bucket, stats);
+            }
+        }
+        self.aggregate_week(at);
+    }
+
+    /// Weeks aren't a rollup of their own, but are built from the days in them
+    fn aggregate_week(&mut self, at: &DateTime<Utc>) {
+        let week = truncate_to_week(at);
+        let end = week + TimeDelta::days(7);
+        let stats = Statistics::combine(
+            self.daily_data.range(week..end).map(|(_, s)| s),
+            span_secs(&self.raw_data, week, end),
+        );
+        if !stats.is_empty() {
+            self.weekly_data.insert(week, stats);
+        }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(hms: &str) -> DateTime<Utc> {
+        format!("2025-06-01T{hms}Z").parse().unwrap()
+    }
+
+    #[test]
+    fn bucketize_weights_by_time() {
+        let history = History::Raw(BTreeMap::from([
+            (at("00:00:00"), 1000),
+            (at("00:50:00"), 4000),
+            (at("01:00:00"), 0),
+        ]));
+        let max_gap = TimeDelta::hours(1);
+        let bucketed = bucketize(&history, Step::Hour, Aggregation::Avg, max_gap);
+        assert_eq!(bucketed[0].timestamp, at("00:00:00"));
+        // 1000 for 50 minutes and 4000 for 10
+        assert_eq!(bucketed[0].value, 1500);
+        assert_eq!(bucketed[0].coverage, Some(1.0));
 
-    pub fn aggregate_historical(&mut self) {
-        self.hourly_data.extend(Self::aggregate_historical_generic(
-            self.raw_data.iter(),
-            truncate_to_hour,
-        ));
-        self.daily_data.extend(Self::aggregate_historical_generic(
-            self.raw_data.iter(),
-            truncate_to_day,
-        ));
-        self.weekly_data.extend(Self::aggregate_historical_generic(
-            self.raw_data.iter(),
-            truncate_to_week,
-        ));
+        let energy = bucketize(&history, Step::Hour, Aggregation::Energy, max_gap);
+        assert_eq!(energy[0].value, 1500);
+
+        let raw = bucketize(&history, Step::Raw, Aggregation::Avg, max_gap);
+        assert_eq!(raw.len(), 3);
+        assert_eq!(raw[1].value, 4000);
+        assert_eq!(raw[1].coverage, None);
+    }
+
+    #[test]
+    fn bucketize_reports_coverage() {
+        let history = History::Raw(BTreeMap::from([
+            (at("00:00:00"), 1000),
+            (at("00:30:00"), 2000),
+            (at("01:30:00"), 0),
+        ]));
+        let bucketed = bucketize(
+            &history,
+            Step::Hour,
+            Aggregation::Avg,
+            TimeDelta::minutes(15),
+        );
+        // each reading only holds for 15 minutes, and nothing covers 01:00
+        assert_eq!(bucketed.len(), 2);
+        assert_eq!(bucketed[0].value, 1500);
+        assert_eq!(bucketed[0].coverage, Some(0.5));
+        assert_eq!(bucketed[1].timestamp, at("01:00:00"));
+    }
+
+    #[test]
+    fn sparse_readings_hold_their_extremes() {
+        let max_gap = TimeDelta::minutes(15);
+        let mut row = TimeSeriesRow::default();
+        for (hms, value) in [("00:00:00", 1000), ("00:10:00", 3000), ("00:20:00", 2000)] {
+            row.append(at(hms), value, max_gap);
+        }
+        let (from, to) = (at("00:00:00"), at("00:25:00"));
+        let raw = History::Raw(row.points(from, to));
+        let rollups = History::Rollup(
+            Rollup::FiveMinutes,
+            row.rollups(Rollup::FiveMinutes, from, to),
+        );
+        for history in [&raw, &rollups] {
+            for aggregation in [Aggregation::Min, Aggregation::Max] {
+                // polls ten minutes apart leave every other bucket with only
+                // the reading that holds into it, which isn't 0
+                let values: Vec<_> = bucketize(history, Step::FiveMinutes, aggregation, max_gap)
+                    .iter()
+                    .map(|point| (point.timestamp, point.value))
+                    .collect();
+                assert_eq!(
+                    values,
+                    [
+                        (at("00:00:00"), 1000),
+                        (at("00:05:00"), 1000),
+                        (at("00:10:00"), 3000),
+                        (at("00:15:00"), 3000),
+                        (at("00:20:00"), 2000),
+                    ],
+                    "{aggregation:?} of {history:?}"
+                );
+            }
+        }
     }
 
-    fn aggregate(&mut self) {
-        if let Some((dt, stat)) = self.aggregate_generic(truncate_to_hour) {
-            self.hourly_data.insert(dt, stat);
+    #[test]
+    fn rollups_bucketize_like_raw_data() {
+        let max_gap = TimeDelta::minutes(15);
+        let mut row = TimeSeriesRow::default();
+        for minute in 0..=120 {
+            let value = if minute % 7 == 0 {
+                -300
+            } else {
+                1000 + minute * 10
+            };
+            row.append(at("00:00:00") + TimeDelta::minutes(minute), value, max_gap);
         }
-        if let Some((dt, stat)) = self.aggregate_generic(truncate_to_day) {
-            self.daily_data.insert(dt, stat);
+        let (from, to) = (at("00:00:00"), at("01:59:59"));
+        let raw = History::Raw(row.points(from, at("02:00:00")));
+        let rollups = History::Rollup(
+            Rollup::FiveMinutes,
+            row.rollups(Rollup::FiveMinutes, from, to),
+        );
+        for aggregation in [Aggregation::Avg, Aggregation::Min, Aggregation::EnergyOut] {
+            let expected = bucketize(&raw, Step::Hour, aggregation, max_gap);
+            let actual = bucketize(&rollups, Step::Hour, aggregation, max_gap);
+            assert_eq!(actual.len(), 2);
+            for (expected, actual) in expected.iter().zip(&actual) {
+                assert_eq!(actual.timestamp, expected.timestamp);
+                assert_eq!(actual.value, expected.value, "{aggregation:?}");
+                assert_eq!(actual.coverage, expected.coverage);
+            }
         }
-        if let Some((dt, stat)) = self.aggregate_generic(truncate_to_week) {
-            self.weekly_data.insert(dt, stat);
+    }
+
+    /// Readings of a daily counter, in local time since that's when the
+    /// Envoy resets it
+    fn local_at(date: &str, hms: &str) -> DateTime<Utc> {
+        format!("{date}T{hms}")
+            .parse::<chrono::NaiveDateTime>()
+            .unwrap()
+            .and_local_timezone(Local)
+            .earliest()
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    /// Feed `readings` to `row`, returning any days they closed
+    fn close_days(
+        row: &mut TimeSeriesRow,
+        readings: &[(&str, &str, Point)],
+    ) -> Vec<(NaiveDate, Point)> {
+        let mut closed = Vec::new();
+        for (date, hms, value) in readings {
+            let dt = local_at(date, hms);
+            closed.extend(row.close_day(dt, *value));
+            row.append(dt, *value, TimeDelta::minutes(15));
         }
+        closed
+    }
+
+    #[test]
+    fn daily_totals_close_when_the_counter_resets() {
+        let mut row = TimeSeriesRow::default();
+        let closed = close_days(
+            &mut row,
+            &[
+                ("2025-06-01", "21:00:00", 9_000_000),
+                ("2025-06-01", "23:55:00", 9_500_000),
+                // the envoy's midnight needn't be exactly ours
+                ("2025-06-02", "00:10:00", 9_500_000),
+                ("2025-06-02", "00:15:00", 0),
+                ("2025-06-02", "00:20:00", 20_000),
+            ],
+        );
+        let date = NaiveDate::from_ymd_opt(2025, 6, 1).unwrap();
+        assert_eq!(closed, [(date, 9_500_000)]);
+        assert_eq!(row.daily_totals, BTreeMap::from([(date, 9_500_000)]));
+    }
+
+    #[test]
+    fn daily_totals_ignore_dips_during_the_day() {
+        let mut row = TimeSeriesRow::default();
+        let closed = close_days(
+            &mut row,
+            &[
+                ("2025-06-01", "12:00:00", 5_000_000),
+                // a glitch, all the way to zero
+                ("2025-06-01", "12:05:00", 0),
+                ("2025-06-01", "12:10:00", 5_100_000),
+                // switching from the production CT to the microinverters
+                ("2025-06-01", "22:00:00", 9_500_000),
+                ("2025-06-01", "22:05:00", 9_350_000),
+            ],
+        );
+        assert_eq!(closed, []);
+        assert!(row.daily_totals.is_empty());
+    }
+
+    #[test]
+    fn daily_totals_close_days_that_never_rose() {
+        let mut row = TimeSeriesRow::default();
+        let closed = close_days(
+            &mut row,
+            &[
+                ("2025-06-01", "12:00:00", 0),
+                ("2025-06-01", "23:55:00", 0),
+                ("2025-06-02", "00:05:00", 0),
+                ("2025-06-02", "00:10:00", 0),
+            ],
+        );
+        assert_eq!(closed, [(NaiveDate::from_ymd_opt(2025, 6, 1).unwrap(), 0)]);
+    }
+
+    #[test]
+    fn daily_total_summary_checks_integrated_total() {
+        let mut row = TimeSeriesRow::default();
+        row.append(at("12:00:00"), 9500, TimeDelta::minutes(15));
+        let summary = row.daily_total_summary(None, Some(9200));
+        assert_eq!(summary.today(), Some(9500));
+        assert_eq!(summary.integrated_delta(), Some(-300));
+        assert_eq!(row.daily_total_summary(None, None).integrated_delta(), None);
     }
 }
@@ -0,0 +1,367 @@
+use anyhow::Context;
+use base64::Engine;
+use chrono::serde::{ts_seconds, ts_seconds_option};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::{Mutex, RwLock};
+use url::Url;
+
+use crate::args::Args;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TokenClaims {
+    #[serde(rename = "aud")]
+    pub serial: Option<String>,
+    pub username: Option<String>,
+    #[serde(rename = "enphaseUser")]
+    pub enphase_user: Option<String>,
+    #[serde(with = "ts_seconds_option", default)]
+    pub iat: Option<DateTime<Utc>>,
+    #[serde(with = "ts_seconds")]
+    pub exp: DateTime<Utc>,
+}
+
+#[derive(Clone)]
+pub struct EnvoyToken {
+    raw: String,
+    pub claims: TokenClaims,
+}
+
+impl std::fmt::Debug for EnvoyToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // don't leak the bearer token itself into logs
+        f.debug_struct("EnvoyToken")
+            .field("claims", &self.claims)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EnvoyToken {
+    /// Decode (but do not verify) the claims of an Enphase-issued JWT
+    pub fn parse(raw: &str) -> anyhow::Result<Self> {
+        let raw = raw.trim();
+        let mut parts = raw.split('.');
+        let (Some(_header), Some(payload), Some(_signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            anyhow::bail!("token does not have three parts");
+        };
+        let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .decode(payload.trim_end_matches('='))
+            .context("token payload is not valid base64")?;
+        let claims: TokenClaims =
+            serde_json::from_slice(&payload).context("token payload is not valid claims")?;
+        Ok(Self {
+            raw: raw.to_owned(),
+            claims,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.raw
+    }
+
+    pub fn expires_at(&self) -> DateTime<Utc> {
+        self.claims.exp
+    }
+}
+
+#[derive(Debug, Clone)]
+struct EnlightenCredentials {
+    username: String,
+    password: String,
+    serial: String,
+    enlighten_url: Url,
+    entrez_url: Url,
+}
+
+#[derive(Deserialize, Debug)]
+struct EnlightenLoginResponse {
+    session_id: String,
+}
+
+#[derive(Serialize, Debug)]
+struct EntrezTokenRequest<'a> {
+    session_id: &'a str,
+    serial_num: &'a str,
+    username: &'a str,
+}
+
+/// Owns the token used to talk to the Envoy, and (if we were given Enlighten
+/// credentials) renews it before it expires
+pub struct TokenManager {
+    token: RwLock<Option<EnvoyToken>>,
+    credentials: Option<EnlightenCredentials>,
+    cache_path: PathBuf,
+    refresh_before: TimeDelta,
+    // only one refresh should be talking to Enlighten at a time
+    refresh_lock: Mutex<()>,
+    client: reqwest::Client,
+}
+
+impl TokenManager {
+    pub async fn initialize(args: &Args) -> anyhow::Result<Self> {
+        let credentials = match (
+            &args.enlighten_username,
+            &args.enlighten_password,
+            &args.envoy_serial,
+        ) {
+            (Some(username), Some(password), Some(serial)) => Some(EnlightenCredentials {
+                username: username.clone(),
+                password: password.clone(),
+                serial: serial.clone(),
+                enlighten_url: args.enlighten_url.clone(),
+                entrez_url: args.entrez_url.clone(),
+            }),
+            (None, None, _) => None,
+            _ => anyhow::bail!(
+                "ENLIGHTEN_USERNAME, ENLIGHTEN_PASSWORD, and ENVOY_SERIAL must be set together"
+            ),
+        };
+        let cache_path = args.state_path.with_extension("token");
+        // unlike the envoy, enlighten has real certificates
+        let client = reqwest::Client::builder()
+            .tls_backend_rustls()
+            .timeout(Duration::from_secs(30))
+            .build()?;
+
+        let static_token = args
+            .envoy_jwt
+            .as_deref()
+            .map(|raw| EnvoyToken::parse(raw).context("ENVOY_JWT is not a valid token"))
+            .transpose()?;
+
+        let refresh_before = args.token_refresh_before();
+        let token = match credentials.as_ref() {
+            // legacy firmware doesn't use tokens at all, so this isn't fatal
+            // until we try to talk to a modern Envoy
+            None => static_token,
+            Some(credentials) => {
+                let existing = [read_cache(&cache_path), static_token]
+                    .into_iter()
+                    .flatten()
+                    .max_by_key(|t| t.expires_at());
+                match existing {
+                    Some(token) if !should_refresh(&token, refresh_before) => Some(token),
+                    existing => match fetch_from_enlighten(&client, credentials, &cache_path).await
+                    {
+                        Ok(token) => Some(token),
+                        // a token that's only close to expiring still works, and
+                        // the refresh task will keep trying to renew it
+                        Err(error) => match existing {
+                            Some(token) if token.expires_at() > Utc::now() => {
+                                tracing::warn!(
+                                    ?error,
+                                    expires_at = %token.expires_at(),
+                                    "unable to renew envoy token; using the existing one for now"
+                                );
+                                Some(token)
+                            }
+                            _ => return Err(error),
+                        },
+                    },
+                }
+            }
+        };
+        if let Some(token) = token.as_ref() {
+            tracing::info!(
+                expires_at = %token.expires_at(),
+                serial = ?token.claims.serial,
+                username = ?token.claims.username,
+                role = ?token.claims.enphase_user,
+                "loaded envoy token"
+            );
+            if token.expires_at() < Utc::now() {
+                tracing::warn!(expires_at = %token.expires_at(), "envoy token has already expired");
+            }
+        } else {
+            tracing::info!("no envoy token configured; only legacy firmware will be supported");
+        }
+        Ok(Self {
+            token: RwLock::new(token),
+            credentials,
+            cache_path,
+            refresh_before,
+            refresh_lock: Mutex::new(()),
+            client,
+        })
+    }
+
+    pub async fn current(&self) -> Option<EnvoyToken> {
+        self.token.read().await.clone()
+    }
+
+    pub async fn require_current(&self) -> anyhow::Result<EnvoyToken> {
+        self.current().await.ok_or_else(|| {
+            anyhow::anyhow!(
+                "either ENVOY_JWT or ENLIGHTEN_USERNAME/ENLIGHTEN_PASSWORD/ENVOY_SERIAL must be set"
+            )
+        })
+    }
+
+    /// Whether we're able to get a new token without human intervention
+    pub fn can_refresh(&self) -> bool {
+        self.credentials.is_some()
+    }
+
+    /// Fetch a new token from Enlighten, unless someone else already did so
+    /// since `stale` was handed out
+    pub async fn refresh(&self, stale: &EnvoyToken) -> anyhow::Result<EnvoyToken> {
+        let _guard = self.refresh_lock.lock().await;
+        if let Some(current) = self.current().await
+            && current.as_str() != stale.as_str()
+        {
+            return Ok(current);
+        }
+        let credentials = self
+            .credentials
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("no enlighten credentials configured"))?;
+        let token = fetch_from_enlighten(&self.client, credentials, &self.cache_path).await?;
+        tracing::info!(expires_at = %token.expires_at(), "renewed envoy token");
+        *self.token.write().await = Some(token.clone());
+        Ok(token)
+    }
+
+    /// Nothing to do without a token, which is normal for legacy firmware
+    pub async fn refresh_if_expiring(&self) -> anyhow::Result<()> {
+        if !self.can_refresh() {
+            return Ok(());
+        }
+        if let Some(current) = self.current().await
+            && should_refresh(&current, self.refresh_before)
+        {
+            self.refresh(&current).await?;
+        }
+        Ok(())
+    }
+}
+
+fn should_refresh(token: &EnvoyToken, refresh_before: TimeDelta) -> bool {
+    token.expires_at() - refresh_before < Utc::now()
+}
+
+fn read_cache(path: &Path) -> Option<EnvoyToken> {
+    let raw = std::fs::read_to_string(path).ok()?;
+    match EnvoyToken::parse(&raw) {
+        Ok(token) => Some(token),
+        Err(error) => {
+            tracing::warn!(?error, ?path, "ignoring invalid cached token");
+            None
+        }
+    }
+}
+
+fn write_cache(path: &Path, token: &EnvoyToken) -> anyhow::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path)?;
+    file.write_all(token.as_str().as_bytes())?;
+    Ok(())
+}
+
+async fn fetch_from_enlighten(
+    client: &reqwest::Client,
+    credentials: &EnlightenCredentials,
+    cache_path: &Path,
+) -> anyhow::Result<EnvoyToken> {
+    let mut login_url = credentials.enlighten_url.clone();
+    login_url.set_path("/login/login.json");
+    tracing::debug!(url = ?login_url, "logging in to enlighten");
+    let login_resp: EnlightenLoginResponse = client
+        .post(login_url)
+        .form(&[
+            ("user[email]", credentials.username.as_str()),
+            ("user[password]", credentials.password.as_str()),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await
+        .context("unable to log in to enlighten")?;
+
+    let mut token_url = credentials.entrez_url.clone();
+    token_url.set_path("/tokens");
+    tracing::debug!(url = ?token_url, "requesting envoy token");
+    let raw = client
+        .post(token_url)
+        .json(&EntrezTokenRequest {
+            session_id: &login_resp.session_id,
+            serial_num: &credentials.serial,
+            username: &credentials.username,
+        })
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let token = EnvoyToken::parse(&raw).context("entrez returned an invalid token")?;
+
+    if let Err(error) = write_cache(cache_path, &token) {
+        tracing::warn!(?error, path = ?cache_path, "unable to cache envoy token");
+    }
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envoy_api::serve_fixtures;
+    use clap::Parser;
+
+    fn token_expiring_in(days: i64) -> String {
+        let claims = serde_json::json!({
+            "aud": "122233445566",
+            "exp": (Utc::now() + TimeDelta::days(days)).timestamp(),
+        });
+        let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(claims.to_string());
+        format!("e30.{payload}.c2ln")
+    }
+
+    #[tokio::test]
+    async fn keeps_an_expiring_token_when_enlighten_is_down() {
+        // enlighten answers everything with a 404
+        let enlighten = serve_fixtures(&[]).await;
+        let state_path =
+            std::env::temp_dir().join(format!("envoyproxy-token-{}.db", std::process::id()));
+        let parse = |jwt: &str| {
+            Args::try_parse_from([
+                "envoyproxy",
+                "--state-path",
+                state_path.to_str().unwrap(),
+                "--envoy-jwt",
+                jwt,
+                "--enlighten-username",
+                "user@example.com",
+                "--enlighten-password",
+                "hunter2",
+                "--envoy-serial",
+                "122233445566",
+                "--enlighten-url",
+                enlighten.as_str(),
+                "--entrez-url",
+                enlighten.as_str(),
+            ])
+            .unwrap()
+        };
+
+        let expiring = token_expiring_in(10);
+        let manager = TokenManager::initialize(&parse(&expiring)).await.unwrap();
+        assert_eq!(manager.current().await.unwrap().as_str(), expiring);
+
+        assert!(
+            TokenManager::initialize(&parse(&token_expiring_in(-1)))
+                .await
+                .is_err()
+        );
+    }
+}